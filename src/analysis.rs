@@ -0,0 +1,937 @@
+use crate::error::{Error, Result};
+use crate::scan::{self, Pattern};
+use crate::{parse, DiceGroup, Explode, Function, Roll, MAX_EXPLOSIONS};
+use rand::Rng;
+use std::collections::BTreeMap;
+use std::fmt;
+
+pub(crate) static QUERY: Pattern = Pattern::new(
+    r"^(?P<roll>.*?)(?P<op>>=|<=|>|<|=)\s*(?P<target>-?\d+)\s*\?\s*$",
+    scan::query,
+);
+
+/// How many outcomes a keep/drop or unique group may have before enumerating them
+/// exactly is refused.
+pub const DEFAULT_FACES_LIMIT: u64 = 1_000_000;
+
+/// How many dice show each value, lowest value first. Dice of every size are pooled, so a 4
+/// on a d6 and a 4 on a d8 are counted together.
+pub fn histogram(values: &[i32]) -> BTreeMap<i32, usize> {
+    let mut counts = BTreeMap::new();
+    for v in values {
+        *counts.entry(*v).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// The value shared by the most dice and how many show it, as when scoring sets in
+/// Yahtzee. Ties go to the higher value.
+pub fn largest_match(values: &[i32]) -> Option<(i32, usize)> {
+    histogram(values)
+        .into_iter()
+        .max_by_key(|&(value, count)| (count, value))
+}
+
+/// How a total is compared against a target.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    AtLeast,
+    MoreThan,
+    AtMost,
+    LessThan,
+    Exactly,
+}
+
+impl Comparison {
+    pub fn holds(self, total: i32, target: i32) -> bool {
+        match self {
+            Comparison::AtLeast => total >= target,
+            Comparison::MoreThan => total > target,
+            Comparison::AtMost => total <= target,
+            Comparison::LessThan => total < target,
+            Comparison::Exactly => total == target,
+        }
+    }
+}
+
+impl From<&str> for Comparison {
+    fn from(s: &str) -> Self {
+        match s {
+            ">=" => Comparison::AtLeast,
+            ">" => Comparison::MoreThan,
+            "<=" => Comparison::AtMost,
+            "<" => Comparison::LessThan,
+            "=" => Comparison::Exactly,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self {
+            Comparison::AtLeast => ">=",
+            Comparison::MoreThan => ">",
+            Comparison::AtMost => "<=",
+            Comparison::LessThan => "<",
+            Comparison::Exactly => "=",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+/// A question about a roll rather than a roll to make, written as the expression, a
+/// comparison and a trailing `?`, as in `1d20+5 >= 15 ?`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    pub roll: Roll,
+    pub comparison: Comparison,
+    pub target: i32,
+}
+
+impl Query {
+    /// The query in `input`, or `None` when it doesn't end in `?` and should be rolled.
+    pub fn parse(input: &str) -> Result<Option<Self>> {
+        if !input.trim_end().ends_with('?') {
+            return Ok(None);
+        }
+        let c = QUERY
+            .captures(input)
+            .ok_or_else(|| Error::MalformedQuery(input.to_string()))?;
+        Ok(Some(Query {
+            roll: parse(c["roll"].trim())?,
+            comparison: Comparison::from(&c["op"]),
+            target: c["target"].parse()?,
+        }))
+    }
+
+    /// The exact chance the comparison holds, as `cast` with the same `crit` would roll it.
+    /// A target the roll can't help but meet, or can never meet, is answered from the
+    /// lowest and highest totals without working out the distribution.
+    pub fn probability(&self, crit: i32, limit: u64) -> Result<f64> {
+        self.roll.validate()?;
+        let (low, high) = (self.roll.min_total(crit), self.roll.max_total(crit));
+        let holds = |total| self.comparison.holds(total, self.target);
+        match (holds(low), holds(high)) {
+            (true, true) => return Ok(1.0),
+            (false, false)
+                if self.comparison != Comparison::Exactly
+                    || !(low..=high).contains(&self.target) =>
+            {
+                return Ok(0.0)
+            }
+            _ => {}
+        }
+
+        Ok(self
+            .roll
+            .distribution(crit, limit)?
+            .iter()
+            .filter(|(v, _)| self.comparison.holds(*v, self.target))
+            .fold(0.0, |total, (_, p)| total + p))
+    }
+}
+
+/// The longest run of consecutive values among the dice, lowest first, as when scoring a
+/// straight: `[5, 3, 4, 3, 1]` has the run 3, 4, 5. Duplicates don't extend a run, and of
+/// equally long runs the lowest is chosen.
+pub fn longest_run(values: &[i32]) -> Option<Vec<i32>> {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+
+    let mut best: Option<&[i32]> = None;
+    let mut start = 0;
+    for i in 1..=sorted.len() {
+        if i == sorted.len() || sorted[i] != sorted[i - 1] + 1 {
+            let run = &sorted[start..i];
+            if best.map_or(true, |b| run.len() > b.len()) {
+                best = Some(run);
+            }
+            start = i;
+        }
+    }
+    best.map(<[i32]>::to_vec)
+}
+
+/// The exact chance of every possible total of a roll.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Distribution {
+    chances: BTreeMap<i32, f64>,
+}
+
+impl Distribution {
+    pub fn constant(value: i32) -> Self {
+        let mut chances = BTreeMap::new();
+        chances.insert(value, 1.0);
+        Distribution { chances }
+    }
+
+    fn from_pairs(pairs: impl IntoIterator<Item = (i32, f64)>) -> Self {
+        let mut chances = BTreeMap::new();
+        for (value, chance) in pairs {
+            *chances.entry(value).or_insert(0.0) += chance;
+        }
+        Distribution { chances }
+    }
+
+    /// The distribution of the sum of two independent totals.
+    pub fn add(&self, other: &Distribution) -> Self {
+        Distribution::from_pairs(
+            self.chances
+                .iter()
+                .flat_map(|(a, p)| other.chances.iter().map(move |(b, q)| (a + b, p * q))),
+        )
+    }
+
+    fn map(&self, f: impl Fn(i32) -> i32) -> Self {
+        Distribution::from_pairs(self.chances.iter().map(|(v, p)| (f(*v), *p)))
+    }
+
+    /// The distribution of the larger (or smaller) of several independent totals.
+    fn select(dists: &[Distribution], highest: bool) -> Self {
+        let mut values: Vec<i32> = dists
+            .iter()
+            .flat_map(|d| d.chances.keys().cloned())
+            .collect();
+        values.sort_unstable();
+        values.dedup();
+
+        // The chance every total is at most (or, for the lowest, at least) each value.
+        let bound = |v: i32| -> f64 {
+            dists
+                .iter()
+                .map(|d| {
+                    d.chances
+                        .iter()
+                        .filter(|(x, _)| if highest { **x <= v } else { **x >= v })
+                        .map(|(_, p)| p)
+                        .sum::<f64>()
+                })
+                .product()
+        };
+
+        let mut pairs = Vec::with_capacity(values.len());
+        for (i, v) in values.iter().enumerate() {
+            let beyond = match highest {
+                true if i > 0 => bound(values[i - 1]),
+                false if i + 1 < values.len() => bound(values[i + 1]),
+                _ => 0.0,
+            };
+            let chance = bound(*v) - beyond;
+            if chance > 0.0 {
+                pairs.push((*v, chance));
+            }
+        }
+        Distribution::from_pairs(pairs)
+    }
+
+    pub fn chance(&self, value: i32) -> f64 {
+        self.chances.get(&value).cloned().unwrap_or(0.0)
+    }
+
+    /// Every possible total with its chance, lowest total first.
+    pub fn iter(&self) -> impl Iterator<Item = (i32, f64)> + '_ {
+        self.chances.iter().map(|(v, p)| (*v, *p))
+    }
+
+    pub fn min(&self) -> i32 {
+        *self
+            .chances
+            .keys()
+            .next()
+            .expect("A distribution is never empty")
+    }
+
+    pub fn max(&self) -> i32 {
+        *self
+            .chances
+            .keys()
+            .next_back()
+            .expect("A distribution is never empty")
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.iter().map(|(v, p)| f64::from(v) * p).sum()
+    }
+
+    /// The mean squared distance of a total from the mean. For a plain dN this is
+    /// `(N² - 1) / 12`, and the variances of independent dice add up; a constant modifier
+    /// doesn't change it.
+    pub fn variance(&self) -> f64 {
+        let mean = self.mean();
+        self.iter()
+            .map(|(v, p)| (f64::from(v) - mean).powi(2) * p)
+            .sum()
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        self.variance().sqrt()
+    }
+
+    /// The chance a total from this distribution is higher than one from `other`, the two
+    /// rolled independently.
+    pub fn chance_above(&self, other: &Distribution) -> f64 {
+        self.iter()
+            .map(|(a, p)| {
+                p * other
+                    .iter()
+                    .take_while(|(b, _)| *b < a)
+                    .map(|(_, q)| q)
+                    .sum::<f64>()
+            })
+            .sum()
+    }
+
+    /// The chance a total from this distribution equals one from `other`.
+    pub fn chance_tied(&self, other: &Distribution) -> f64 {
+        self.iter().map(|(a, p)| p * other.chance(a)).sum()
+    }
+
+    /// The most likely total; ties go to the lower total.
+    pub fn mode(&self) -> i32 {
+        let mut best = self.min();
+        for (v, p) in self.iter() {
+            if p > self.chance(best) {
+                best = v;
+            }
+        }
+        best
+    }
+}
+
+impl DiceGroup {
+    /// The highest total the group can roll: every die shows the highest face it can (for
+    /// unique dice, the highest faces left) and explodes as far as it can, then keep/drop
+    /// applies as usual.
+    pub fn max_total(&self) -> i32 {
+        self.bound(true)
+    }
+
+    /// The lowest total the group can roll, the counterpart to `max_total`.
+    pub fn min_total(&self) -> i32 {
+        self.bound(false)
+    }
+
+    fn bound(&self, highest: bool) -> i32 {
+        match self.negative {
+            true => -self.unsigned_bound(!highest),
+            false => self.unsigned_bound(highest),
+        }
+    }
+
+    /// The bound as if the group were added, even when it's subtracted.
+    fn unsigned_bound(&self, highest: bool) -> i32 {
+        let mut faces: Vec<i32> = (1..)
+            .zip(self.chances())
+            .filter(|(_, p)| *p > 0.0)
+            .map(|(face, _)| face)
+            .collect();
+        if highest {
+            faces.reverse();
+        }
+        let firsts: Vec<i32> = match self.unique {
+            true => faces.iter().take(self.count as usize).cloned().collect(),
+            false => vec![faces[0]; self.count as usize],
+        };
+
+        let max = self.die.sides as i32;
+        let lowest_next = (1..)
+            .zip(self.die.chances())
+            .find(|(_, p)| *p > 0.0)
+            .map_or(max, |(face, _)| face);
+        let exploded = |face: i32| {
+            let value = match (face == max, highest || lowest_next == max) {
+                (false, _) => face,
+                (true, true) => max * (MAX_EXPLOSIONS as i32 + 1),
+                (true, false) => max + lowest_next,
+            };
+            self.explode_cap.map_or(value, |cap| value.min(cap))
+        };
+        let scores: Vec<i32> = firsts
+            .iter()
+            .enumerate()
+            .map(|(i, face)| match self.explode {
+                Some(Explode::Each) => exploded(*face),
+                Some(Explode::Highest) if i == 0 => exploded(*face),
+                _ => *face,
+            })
+            .map(|n| self.adjust(n))
+            .collect();
+        let kept = match &self.keep {
+            Some(keep) => keep.apply(&scores),
+            None => vec![true; scores.len()],
+        };
+        scores
+            .iter()
+            .zip(kept)
+            .filter(|(_, k)| *k)
+            .map(|(s, _)| s)
+            .sum()
+    }
+
+    /// The exact distribution of the group's total. Plain dice are convolved; keep/drop and
+    /// unique groups are enumerated outcome by outcome, up to `limit` outcomes.
+    pub fn distribution(&self, limit: u64) -> Result<Distribution> {
+        let dist = self.unsigned_distribution(limit)?;
+        Ok(match self.negative {
+            true => dist.map(|v| -v),
+            false => dist,
+        })
+    }
+
+    fn unsigned_distribution(&self, limit: u64) -> Result<Distribution> {
+        if self.explode.is_some() {
+            return Err(Error::NotEnumerable(format!(
+                "exploding {}d{}",
+                self.count, self.die.sides
+            ))
+            .into());
+        }
+        if u64::from(self.die.sides) > limit {
+            return Err(Error::TooManyOutcomes(format!("a d{}", self.die.sides), limit).into());
+        }
+
+        let faces: Vec<(i32, f64)> = (1..)
+            .zip(self.chances())
+            .filter(|(_, p)| *p > 0.0)
+            .collect();
+        if self.keep.is_none() && !self.unique {
+            let die = Distribution::from_pairs(faces.iter().map(|(v, p)| (self.adjust(*v), *p)));
+            return Ok((0..self.count).fold(Distribution::constant(0), |acc, _| acc.add(&die)));
+        }
+
+        let outcomes = (faces.len() as u64)
+            .checked_pow(self.count)
+            .filter(|n| *n <= limit)
+            .ok_or_else(|| {
+                Error::TooManyOutcomes(format!("{}d{}", self.count, self.die.sides), limit)
+            })?;
+
+        let mut pairs = Vec::with_capacity(outcomes as usize);
+        let mut indices = vec![0; self.count as usize];
+        for _ in 0..outcomes {
+            let mut chance = 1.0;
+            let mut used = 0.0;
+            for (j, i) in indices.iter().enumerate() {
+                let (_, p) = faces[*i];
+                if self.unique {
+                    if indices[..j].contains(i) {
+                        chance = 0.0;
+                        break;
+                    }
+                    // Duplicates are rerolled, so each die only picks from the faces left.
+                    chance *= p / (1.0 - used);
+                    used += p;
+                } else {
+                    chance *= p;
+                }
+            }
+
+            if chance > 0.0 {
+                let scores: Vec<i32> = indices.iter().map(|i| self.adjust(faces[*i].0)).collect();
+                let kept = match &self.keep {
+                    Some(keep) => keep.apply(&scores),
+                    None => vec![true; scores.len()],
+                };
+                let total = scores
+                    .iter()
+                    .zip(kept)
+                    .filter(|(_, k)| *k)
+                    .map(|(s, _)| s)
+                    .sum();
+                pairs.push((total, chance));
+            }
+
+            for i in indices.iter_mut().rev() {
+                *i += 1;
+                if *i < faces.len() {
+                    break;
+                }
+                *i = 0;
+            }
+        }
+        Ok(Distribution::from_pairs(pairs))
+    }
+}
+
+impl Function {
+    pub fn max_total(&self, crit: i32) -> i32 {
+        self.bound(crit, true)
+    }
+
+    pub fn min_total(&self, crit: i32) -> i32 {
+        self.bound(crit, false)
+    }
+
+    fn bound(&self, crit: i32, highest: bool) -> i32 {
+        let bounds = self.args().iter().map(|a| a.bound(crit, highest));
+        match self {
+            Function::Max(_) => bounds
+                .max()
+                .expect("Functions are validated to have arguments"),
+            Function::Min(_) => bounds
+                .min()
+                .expect("Functions are validated to have arguments"),
+            Function::Avg(roll) => roll.average(crit),
+            Function::Crit(roll) | Function::Best { roll, .. } => roll.bound(crit, highest),
+        }
+    }
+
+    pub fn distribution(&self, crit: i32, limit: u64) -> Result<Distribution> {
+        match self {
+            Function::Avg(roll) => return Ok(Distribution::constant(roll.average(crit))),
+            Function::Crit(roll) => return roll.distribution(crit, limit),
+            Function::Best { times, roll } => {
+                let dist = roll.distribution(crit, limit)?;
+                return Ok(Distribution::select(&vec![dist; *times as usize], true));
+            }
+            _ => {}
+        }
+
+        let dists = self
+            .args()
+            .iter()
+            .map(|a| a.distribution(crit, limit))
+            .collect::<Result<Vec<Distribution>>>()?;
+        Ok(Distribution::select(
+            &dists,
+            matches!(self, Function::Max(_)),
+        ))
+    }
+}
+
+impl Roll {
+    /// The exact distribution of the total, as `cast` with the same `crit` would produce.
+    pub fn distribution(&self, crit: i32, limit: u64) -> Result<Distribution> {
+        let mut dice = Distribution::constant(0);
+        for group in &self.dice {
+            dice = dice.add(&group.distribution(limit)?);
+        }
+
+        let mut total = dice.map(|v| v * self.untagged_crit(crit));
+        for function in &self.functions {
+            total = total.add(&function.distribution(self.function_crit(function, crit), limit)?);
+        }
+        let constant: i32 = self.constants.iter().sum();
+        Ok(total.map(|v| v + constant))
+    }
+
+    /// The highest total the roll can reach with the given `crit`, even where its
+    /// distribution is too large to enumerate. The roll should already be validated.
+    pub fn max_total(&self, crit: i32) -> i32 {
+        self.bound(crit, true)
+    }
+
+    /// The lowest total the roll can reach, the counterpart to `max_total`.
+    pub fn min_total(&self, crit: i32) -> i32 {
+        self.bound(crit, false)
+    }
+
+    fn bound(&self, crit: i32, highest: bool) -> i32 {
+        let dice: i32 = self.dice.iter().map(|g| g.bound(highest)).sum();
+        let called: i32 = self
+            .functions
+            .iter()
+            .map(|f| f.bound(self.function_crit(f, crit), highest))
+            .sum();
+        let constant: i32 = self.constants.iter().sum();
+        dice * self.untagged_crit(crit) + called + constant
+    }
+
+    /// An approximate distribution from casting the roll `samples` times, for rolls too
+    /// large (or exploding) to enumerate exactly.
+    pub fn sample_distribution<R: Rng + ?Sized>(
+        &self,
+        crit: i32,
+        rng: &mut R,
+        samples: usize,
+    ) -> Result<Distribution> {
+        if samples == 0 {
+            return Err(Error::ZeroSamples.into());
+        }
+        let share = 1.0 / samples as f64;
+        let totals = self.cast_n(samples, crit, rng)?;
+        Ok(Distribution::from_pairs(
+            totals.iter().map(|res| (res.total, share)),
+        ))
+    }
+
+    /// An estimate of the chance that the total is at least `target`, from casting the roll
+    /// `samples` times. Use `distribution` for the exact chance where it can be enumerated.
+    pub fn probability_at_least<R: Rng + ?Sized>(
+        &self,
+        target: i32,
+        rng: &mut R,
+        samples: usize,
+    ) -> Result<f64> {
+        if samples == 0 {
+            return Err(Error::ZeroSamples.into());
+        }
+        let hits = self
+            .cast_n(samples, 1, rng)?
+            .iter()
+            .filter(|res| res.total >= target)
+            .count();
+        Ok(hits as f64 / samples as f64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parse, seeded_rng};
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn test_distribution_2d6() {
+        let dist = parse("2d6")
+            .unwrap()
+            .distribution(1, DEFAULT_FACES_LIMIT)
+            .unwrap();
+        assert_eq!(2, dist.min());
+        assert_eq!(12, dist.max());
+        for v in 2..=12_i32 {
+            let ways = 6 - (7 - v).abs();
+            assert!(close(f64::from(ways) / 36.0, dist.chance(v)), "{}", v);
+        }
+        assert!(close(7.0, dist.mean()));
+        assert!(close(1.0, dist.iter().map(|(_, p)| p).sum()));
+    }
+
+    #[test]
+    fn test_distribution_reroll() {
+        let dist = parse("1d6r[1,2]")
+            .unwrap()
+            .distribution(1, DEFAULT_FACES_LIMIT)
+            .unwrap();
+        assert!(close(1.0 / 18.0, dist.chance(1)));
+        assert!(close(2.0 / 9.0, dist.chance(6)));
+        assert!(close(1.0, dist.iter().map(|(_, p)| p).sum()));
+    }
+
+    #[test]
+    fn test_probability_at_least() {
+        let roll = parse("2d6").unwrap();
+        let (_, mut rng) = seeded_rng(Some(3));
+        assert_eq!(1.0, roll.probability_at_least(2, &mut rng, 500).unwrap());
+        assert_eq!(0.0, roll.probability_at_least(13, &mut rng, 500).unwrap());
+        let err = roll.probability_at_least(2, &mut rng, 0).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(Error::ZeroSamples)));
+
+        let estimate = roll.probability_at_least(7, &mut rng, 10_000).unwrap();
+        assert!((estimate - 21.0 / 36.0).abs() < 0.03, "{}", estimate);
+        assert!(Roll::new().probability_at_least(0, &mut rng, 10).is_err());
+    }
+
+    #[test]
+    fn test_distribution_crit_tagging() {
+        let dist = parse("crit(1d2)+1d2")
+            .unwrap()
+            .distribution(2, DEFAULT_FACES_LIMIT)
+            .unwrap();
+        let values: Vec<i32> = dist.iter().map(|(v, _)| v).collect();
+        assert_eq!(vec![3, 4, 5, 6], values);
+        assert!(close(0.25, dist.chance(3)));
+    }
+
+    #[test]
+    fn test_query() {
+        assert_eq!(None, Query::parse("1d20+5").unwrap());
+        let query = Query::parse("1d20+5 >= 15 ?").unwrap().unwrap();
+        assert_eq!(parse("1d20+5").unwrap(), query.roll);
+        assert_eq!(Comparison::AtLeast, query.comparison);
+        assert_eq!(15, query.target);
+        assert!(close(
+            0.55,
+            query.probability(1, DEFAULT_FACES_LIMIT).unwrap()
+        ));
+
+        for (input, chance) in &[
+            ("2d6>7?", 15.0 / 36.0),
+            ("2d6 = 7 ?", 6.0 / 36.0),
+            ("2d6<=3?", 3.0 / 36.0),
+            ("2d6 < -1 ?", 0.0),
+        ] {
+            let query = Query::parse(input).unwrap().unwrap();
+            assert!(
+                close(*chance, query.probability(1, DEFAULT_FACES_LIMIT).unwrap()),
+                "{}",
+                input
+            );
+        }
+
+        assert!(Query::parse("1d20+5 ?").is_err());
+    }
+
+    #[test]
+    fn test_query_out_of_range() {
+        let chance = |input| {
+            Query::parse(input)
+                .unwrap()
+                .unwrap()
+                .probability(1, DEFAULT_FACES_LIMIT)
+                .unwrap()
+        };
+        for input in &["1d20+5 >= 30 ?", "1d20+5 > 25 ?", "2d6 = 13 ?", "2d6 < 2 ?"] {
+            assert_eq!(0.0, chance(input), "{}", input);
+        }
+        for input in &[
+            "1d20+5 >= 6 ?",
+            "1d20+5 <= 25 ?",
+            "1d1+3 = 4 ?",
+            "3d6klnz > 0 ?",
+        ] {
+            assert_eq!(1.0, chance(input), "{}", input);
+        }
+
+        // Exploding dice can't be enumerated, but these don't need to be.
+        assert_eq!(1.0, chance("1d6! >= 1 ?"));
+        assert_eq!(0.0, chance("1d6!c20 > 20 ?"));
+        assert!(Query::parse("1d6! >= 7 ?")
+            .unwrap()
+            .unwrap()
+            .probability(1, DEFAULT_FACES_LIMIT)
+            .is_err());
+    }
+
+    #[test]
+    fn test_distribution_cursed() {
+        let dist = parse("2d4-max")
+            .unwrap()
+            .distribution(1, DEFAULT_FACES_LIMIT)
+            .unwrap();
+        assert_eq!(2, dist.min());
+        assert_eq!(6, dist.max());
+        assert!(close(1.0 / 9.0, dist.chance(6)));
+    }
+
+    #[test]
+    fn test_mode() {
+        let dist = parse("2d6")
+            .unwrap()
+            .distribution(1, DEFAULT_FACES_LIMIT)
+            .unwrap();
+        assert_eq!(7, dist.mode());
+        assert_eq!(1, Distribution::constant(1).mode());
+
+        let roll = parse("1d2").unwrap();
+        assert_eq!(1, roll.distribution(1, DEFAULT_FACES_LIMIT).unwrap().mode());
+    }
+
+    #[test]
+    fn test_sample_distribution() {
+        let (_, mut rng) = seeded_rng(Some(8));
+        let dist = parse("2d6+1")
+            .unwrap()
+            .sample_distribution(1, &mut rng, 20_000)
+            .unwrap();
+        assert_eq!(3, dist.min());
+        assert_eq!(13, dist.max());
+        assert_eq!(8, dist.mode());
+        assert!((dist.mean() - 8.0).abs() < 0.1);
+        assert!(close(1.0, dist.iter().map(|(_, p)| p).sum()));
+
+        let err = parse("2d6")
+            .unwrap()
+            .sample_distribution(1, &mut rng, 0)
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(Error::ZeroSamples)));
+    }
+
+    #[test]
+    fn test_max_total() {
+        for input in &[
+            "2d6+3",
+            "4d6dl1+1d8kh1",
+            "3u6dh1+1d4-max",
+            "max(1d20,2d8)+min(1d12,1d4)",
+            "crit(1d8)+2d6-1",
+            "4dF+per1",
+            "3d6klnz",
+            "1d6-1d4",
+            "10-2d6kh1",
+        ] {
+            let roll = parse(input).unwrap();
+            for crit in 1..=2 {
+                let dist = roll.distribution(crit, DEFAULT_FACES_LIMIT).unwrap();
+                assert_eq!(dist.max(), roll.max_total(crit), "{} x{}", input, crit);
+                assert_eq!(dist.min(), roll.min_total(crit), "{} x{}", input, crit);
+            }
+        }
+
+        let roll = parse("1d6-1d4").unwrap();
+        assert_eq!((-3, 5), (roll.min_total(1), roll.max_total(1)));
+        let dist = roll.distribution(1, DEFAULT_FACES_LIMIT).unwrap();
+        assert!(close(1.0 / 24.0, dist.chance(-3)));
+        assert!(close(1.0, dist.mean()));
+
+        assert_eq!(18, parse("3d6!c6").unwrap().max_total(1));
+        assert_eq!(20, parse("2d6!c10").unwrap().max_total(1));
+        assert_eq!(606 + 6, parse("2d6!h").unwrap().max_total(1));
+        assert_eq!(36, parse("100d6kh6").unwrap().max_total(1));
+
+        for input in &[
+            "2d6+3",
+            "4d6dl1",
+            "3u6dh1",
+            "min(1d12,1d4)-2",
+            "4dF",
+            "2d4r[1]",
+        ] {
+            let roll = parse(input).unwrap();
+            let dist = roll.distribution(1, DEFAULT_FACES_LIMIT).unwrap();
+            assert_eq!(dist.min(), roll.min_total(1), "{}", input);
+        }
+        assert_eq!(2, parse("2d6!").unwrap().min_total(1));
+        assert_eq!(101, parse("1d1!").unwrap().min_total(1));
+        assert_eq!(20, parse("1d6w[0,0,0,0,0,1]!c20").unwrap().min_total(1));
+    }
+
+    #[test]
+    fn test_distribution_modifiers() {
+        let dist = parse("1d4+per1+3")
+            .unwrap()
+            .distribution(2, DEFAULT_FACES_LIMIT)
+            .unwrap();
+        let values: Vec<i32> = dist.iter().map(|(v, _)| v).collect();
+        assert_eq!(vec![7, 9, 11, 13], values);
+    }
+
+    #[test]
+    fn test_std_dev() {
+        let d6 = parse("1d6").unwrap().distribution(1, 1000).unwrap();
+        assert!((d6.std_dev() - 1.7078).abs() < 1e-4);
+        assert!(close(35.0 / 12.0, d6.variance()));
+
+        let dist = |input| parse(input).unwrap().distribution(1, 1000).unwrap();
+        assert!(close(
+            35.0 / 12.0 + 99.0 / 12.0,
+            dist("1d6+1d10+7").variance()
+        ));
+        assert!(close(0.0, dist("5").variance()));
+        assert!(dist("2d20kh1").variance() < dist("1d20").variance());
+    }
+
+    #[test]
+    fn test_chance_above() {
+        let d12 = parse("1d12+3").unwrap().distribution(1, 1000).unwrap();
+        let d6 = parse("2d6+3").unwrap().distribution(1, 1000).unwrap();
+        let (above, tied, below) = (
+            d12.chance_above(&d6),
+            d12.chance_tied(&d6),
+            d6.chance_above(&d12),
+        );
+        assert!(close(1.0, above + tied + below));
+        assert!(close(1.0 / 12.0, tied));
+        assert!(close(above, 5.0 / 12.0));
+
+        let d20 = parse("1d20").unwrap().distribution(1, 1000).unwrap();
+        assert!(close(0.475, d20.chance_above(&d20)));
+    }
+
+    #[test]
+    fn test_distribution_keep_and_max() {
+        let keep = parse("2d20kh1")
+            .unwrap()
+            .distribution(1, DEFAULT_FACES_LIMIT)
+            .unwrap();
+        let max = parse("max(1d20,1d20)")
+            .unwrap()
+            .distribution(1, DEFAULT_FACES_LIMIT)
+            .unwrap();
+        let min = parse("min(1d20,1d20)")
+            .unwrap()
+            .distribution(1, DEFAULT_FACES_LIMIT)
+            .unwrap();
+        for v in 1..=20 {
+            let expected = f64::from(2 * v - 1) / 400.0;
+            assert!(close(expected, keep.chance(v)));
+            assert!(close(expected, max.chance(v)));
+            assert!(close(expected, min.chance(21 - v)));
+        }
+    }
+
+    #[test]
+    fn test_distribution_unique() {
+        let dist = parse("2u3")
+            .unwrap()
+            .distribution(1, DEFAULT_FACES_LIMIT)
+            .unwrap();
+        assert!(close(1.0 / 3.0, dist.chance(3)));
+        assert!(close(1.0 / 3.0, dist.chance(4)));
+        assert!(close(1.0 / 3.0, dist.chance(5)));
+    }
+
+    #[test]
+    fn test_distribution_limits() {
+        assert!(parse("20d6").unwrap().distribution(1, 10).is_ok());
+        let err = parse("20d6kh3")
+            .unwrap()
+            .distribution(1, DEFAULT_FACES_LIMIT)
+            .unwrap_err();
+        assert_eq!(
+            "Too many outcomes to enumerate 20d6: more than 1000000",
+            err.to_string()
+        );
+        assert!(parse("2d6kh1").unwrap().distribution(1, 36).is_ok());
+        assert!(parse("2d6kh1").unwrap().distribution(1, 35).is_err());
+        assert!(parse("1d2000000")
+            .unwrap()
+            .distribution(1, DEFAULT_FACES_LIMIT)
+            .is_err());
+        assert!(parse("1d6!")
+            .unwrap()
+            .distribution(1, DEFAULT_FACES_LIMIT)
+            .is_err());
+    }
+
+    #[test]
+    fn test_largest_match() {
+        assert_eq!(Some((4, 3)), largest_match(&[4, 1, 4, 2, 4, 1]));
+        assert_eq!(Some((6, 1)), largest_match(&[1, 6, 3]));
+        assert_eq!(None, largest_match(&[]));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let counts: Vec<(i32, usize)> = histogram(&[4, 1, 4, 2, 1, 1]).into_iter().collect();
+        assert_eq!(vec![(1, 3), (2, 1), (4, 2)], counts);
+        assert!(histogram(&[]).is_empty());
+
+        let (_, mut rng) = seeded_rng(Some(7));
+        let res = parse("10d6+3d8").unwrap().cast(1, &mut rng).unwrap();
+        let counts = histogram(&res.values());
+        assert_eq!(13, counts.values().sum::<usize>());
+        for (value, count) in counts {
+            assert_eq!(count, res.values().iter().filter(|v| **v == value).count());
+        }
+    }
+
+    #[test]
+    fn test_largest_match_tie() {
+        assert_eq!(Some((5, 2)), largest_match(&[2, 5, 2, 5, 1]));
+        assert_eq!(Some((5, 2)), largest_match(&[5, 2, 5, 2, 1]));
+    }
+
+    #[test]
+    fn test_longest_run() {
+        assert_eq!(Some(vec![3, 4, 5]), longest_run(&[5, 3, 4, 3, 1]));
+        assert_eq!(Some(vec![1, 2]), longest_run(&[5, 2, 1, 6]));
+        assert_eq!(Some(vec![4]), longest_run(&[4, 4, 4]));
+        assert_eq!(Some(vec![-1, 0, 1]), longest_run(&[1, -1, 0, 0]));
+        assert_eq!(None, longest_run(&[]));
+    }
+
+    #[test]
+    fn test_largest_match_kept_only() {
+        let (_, mut rng) = seeded_rng(Some(12));
+        let res = parse("5d6kh3").unwrap().cast(1, &mut rng).unwrap();
+        let (value, count) = largest_match(&res.values()).unwrap();
+        assert!(count <= 3);
+        assert_eq!(count, res.values().iter().filter(|v| **v == value).count());
+    }
+}
@@ -0,0 +1,238 @@
+use crate::error::{Error, Result};
+use crate::{DiceGroup, Explode, Keep, Roll, RollResult};
+use serde::Serialize;
+
+/// A cast roll in the shape Foundry VTT stores rolls in chat messages, for pasting into
+/// macros. Only the notation Foundry shares with us can be expressed: plain and Fudge dice
+/// with keep/drop, exploding and rerolls, plus flat modifiers.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FoundryRoll {
+    pub class: &'static str,
+    pub formula: String,
+    pub terms: Vec<Term>,
+    pub total: i32,
+    pub evaluated: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "class")]
+pub enum Term {
+    Die {
+        number: u32,
+        faces: u32,
+        modifiers: Vec<String>,
+        results: Vec<DieTerm>,
+    },
+    FateDie {
+        number: u32,
+        faces: u32,
+        modifiers: Vec<String>,
+        results: Vec<DieTerm>,
+    },
+    OperatorTerm {
+        operator: &'static str,
+    },
+    NumericTerm {
+        number: i32,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DieTerm {
+    pub result: i32,
+    pub active: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub exploded: bool,
+}
+
+impl FoundryRoll {
+    /// Describes `res`, the result of casting `roll` with the given `crit`.
+    pub fn new(roll: &Roll, res: &RollResult, crit: i32) -> Result<Self> {
+        let unsupported = |what: String| Err(Error::NotExportable(what, "Foundry VTT").into());
+        if crit != 1 {
+            return unsupported(format!("a x{} crit", crit));
+        }
+        if let Some(function) = roll.functions.first() {
+            return unsupported(function.to_string());
+        }
+        if let Some(symbols) = roll.symbols.first() {
+            return unsupported(symbols.to_string());
+        }
+
+        let mut formula = Vec::new();
+        let mut terms = Vec::new();
+        for (group, result) in roll.dice.iter().zip(&res.groups) {
+            let modifiers = match modifiers(group) {
+                Some(modifiers) if !(group.negative && terms.is_empty()) => modifiers,
+                _ => return unsupported(group.to_string()),
+            };
+            if !terms.is_empty() {
+                let operator = match group.negative {
+                    true => "-",
+                    false => "+",
+                };
+                formula.push(operator.to_string());
+                terms.push(Term::OperatorTerm { operator });
+            }
+            let sides = match group.fudge {
+                true => "F".to_string(),
+                false => group.die.sides.to_string(),
+            };
+            formula.push(format!("{}d{}{}", group.count, sides, modifiers.concat()));
+
+            let results = result
+                .rolls
+                .iter()
+                .map(|r| DieTerm {
+                    result: r.value,
+                    active: r.kept,
+                    exploded: r.explosions > 0,
+                })
+                .collect();
+            let (number, faces) = (group.count, group.die.sides);
+            terms.push(match group.fudge {
+                true => Term::FateDie {
+                    number,
+                    faces,
+                    modifiers,
+                    results,
+                },
+                false => Term::Die {
+                    number,
+                    faces,
+                    modifiers,
+                    results,
+                },
+            });
+        }
+
+        for constant in &roll.constants {
+            let operator = match *constant < 0 {
+                true => "-",
+                false => "+",
+            };
+            if !terms.is_empty() {
+                formula.push(operator.to_string());
+                terms.push(Term::OperatorTerm { operator });
+            }
+            let number = match terms.is_empty() {
+                true => *constant,
+                false => constant.abs(),
+            };
+            formula.push(number.to_string());
+            terms.push(Term::NumericTerm { number });
+        }
+
+        Ok(FoundryRoll {
+            class: "Roll",
+            formula: formula.join(" "),
+            terms,
+            total: res.total,
+            evaluated: true,
+        })
+    }
+}
+
+/// The group's options in Foundry's notation, or `None` if it has one Foundry lacks.
+fn modifiers(group: &DiceGroup) -> Option<Vec<String>> {
+    let plain = !group.unique
+        && !group.cursed
+        && group.die.weights.is_none()
+        && group.per_die == 0
+        && group.floor.is_none()
+        && group.ceiling.is_none()
+        && group.explode_cap.is_none();
+    if !plain {
+        return None;
+    }
+
+    let mut modifiers: Vec<String> = group.reroll.iter().map(|f| format!("r{}", f)).collect();
+    match group.explode {
+        Some(Explode::Each) => modifiers.push("x".to_string()),
+        Some(Explode::Highest) => return None,
+        None => {}
+    }
+    match &group.keep {
+        Some(Keep::LowestNonzero) => return None,
+        Some(Keep::SubtractLowest(n)) => modifiers.push(format!("dl{}", n)),
+        Some(keep) => modifiers.push(keep.to_string()),
+        None => {}
+    }
+    Some(modifiers)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parse, seeded_rng};
+    use serde_json::json;
+
+    #[test]
+    fn test_foundry_roll() {
+        let roll = parse("1d20+5").unwrap();
+        let (_, mut rng) = seeded_rng(Some(4));
+        let res = roll.cast(1, &mut rng).unwrap();
+        let natural = res.groups[0].rolls[0].value;
+
+        let foundry = FoundryRoll::new(&roll, &res, 1).unwrap();
+        assert_eq!(
+            json!({
+                "class": "Roll",
+                "formula": "1d20 + 5",
+                "terms": [
+                    {
+                        "class": "Die",
+                        "number": 1,
+                        "faces": 20,
+                        "modifiers": [],
+                        "results": [{ "result": natural, "active": true }],
+                    },
+                    { "class": "OperatorTerm", "operator": "+" },
+                    { "class": "NumericTerm", "number": 5 },
+                ],
+                "total": natural + 5,
+                "evaluated": true,
+            }),
+            serde_json::to_value(&foundry).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_foundry_modifiers() {
+        let roll = parse("4d6r[1]dl1+2dF-1").unwrap();
+        let (_, mut rng) = seeded_rng(Some(4));
+        let res = roll.cast(1, &mut rng).unwrap();
+        let foundry = FoundryRoll::new(&roll, &res, 1).unwrap();
+        assert_eq!("4d6r1dl1 + 2dF - 1", foundry.formula);
+        match &foundry.terms[0] {
+            Term::Die { results, .. } => {
+                assert_eq!(3, results.iter().filter(|r| r.active).count())
+            }
+            term => panic!("Expected a Die, got {:?}", term),
+        }
+        assert!(matches!(foundry.terms[2], Term::FateDie { number: 2, .. }));
+
+        for input in &["2d6!h", "3u6", "1d8+per1", "max(1d4,1d6)", "1d20-max"] {
+            let roll = parse(input).unwrap();
+            let res = roll.cast(1, &mut rng).unwrap();
+            let err = FoundryRoll::new(&roll, &res, 1).unwrap_err();
+            assert!(
+                matches!(err.downcast_ref::<Error>(), Some(Error::NotExportable(..))),
+                "{}",
+                input
+            );
+        }
+        let roll = parse("1d8").unwrap();
+        let res = roll.cast(2, &mut rng).unwrap();
+        assert!(FoundryRoll::new(&roll, &res, 2).is_err());
+
+        let roll = parse("1d8-1d4").unwrap();
+        let res = roll.cast(1, &mut rng).unwrap();
+        let foundry = FoundryRoll::new(&roll, &res, 1).unwrap();
+        assert_eq!("1d8 - 1d4", foundry.formula);
+        assert_eq!(res.total, foundry.total);
+        let roll = parse("-1d4+3").unwrap();
+        let res = roll.cast(1, &mut rng).unwrap();
+        assert!(FoundryRoll::new(&roll, &res, 1).is_err());
+    }
+}
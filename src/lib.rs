@@ -0,0 +1,3595 @@
+use error::{Error, Result};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::{OsRng, StdRng};
+use rand::{Rng, RngCore, SeedableRng};
+use scan::Pattern;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::{Range, RangeInclusive};
+use std::str::FromStr;
+
+pub mod analysis;
+pub mod foundry;
+pub mod replay;
+mod scan;
+pub mod table;
+
+pub mod error {
+    pub use anyhow::{bail, Context, Result};
+    use thiserror::Error;
+
+    #[derive(Error, Debug)]
+    pub enum Error {
+        #[error("Unbalanced parentheses in {0:?}")]
+        UnbalancedParens(String),
+        #[error("{0}() needs at least one argument")]
+        EmptyFunction(String),
+        #[error("{name}() takes {expected} argument(s), got {got}")]
+        ArgumentCount {
+            name: String,
+            expected: usize,
+            got: usize,
+        },
+        #[error("Malformed die {0:?}: expected a number of sides after it")]
+        MalformedDie(String),
+        #[error("Crit range must be between 2 and 20, got {0}")]
+        InvalidCritRange(u32),
+        #[error("Invalid weights for a d{sides}: {reason}")]
+        InvalidWeights { sides: u32, reason: String },
+        #[error("Cannot {keep} from {count} dice")]
+        InvalidKeep { keep: String, count: u32 },
+        #[error("Dice must have at least one side")]
+        NoSides,
+        #[error("Nothing to roll")]
+        EmptyRoll,
+        #[error("Too many outcomes to enumerate {0}: more than {1}")]
+        TooManyOutcomes(String, u64),
+        #[error("Cannot enumerate the outcomes of {0}")]
+        NotEnumerable(String),
+        #[error("Cannot roll {count} unique results on a d{sides}")]
+        ImpossibleUnique { count: u32, sides: u32 },
+        #[error("Explosion cap {cap} is below the highest face of a d{sides}")]
+        InvalidExplodeCap { cap: i32, sides: u32 },
+        #[error("Malformed table line {line}: {text:?}")]
+        MalformedTableLine { line: usize, text: String },
+        #[error("No table entry for {0}")]
+        NoTableEntry(i32),
+        #[error("Unexpected character {0:?} in {1:?}")]
+        UnexpectedChar(char, String),
+        #[error("Invalid argument {arg:?} to {name}()")]
+        InvalidArgument { name: String, arg: String },
+        #[error("Unknown RNG {0:?}: expected thread, secure or seeded")]
+        UnknownRng(String),
+        #[error("{1} has no equivalent of {0}")]
+        NotExportable(String, &'static str),
+        #[error("A cursed d{0} has no faces left to roll")]
+        CursedDie(u32),
+        #[error("Malformed query {0:?}: expected a comparison such as `1d20+5 >= 15 ?`")]
+        MalformedQuery(String),
+        #[error("Malformed replay token {0:?}")]
+        MalformedToken(String),
+        #[error("Cannot clamp dice to {low}..={high}: the low end is above the high end")]
+        InvalidClamp { low: i32, high: i32 },
+        #[error("Cannot roll {count} dice at once: at most {max}")]
+        TooManyDice { count: u32, max: u32 },
+        #[error("Cannot roll a d{sides}: dice have at most {max} sides")]
+        TooManySides { sides: u32, max: u32 },
+        #[error("{roll} could total more than {max}")]
+        TotalTooLarge { roll: String, max: i32 },
+        #[error("Invalid count in {token:?}: {source}")]
+        InvalidCount {
+            token: String,
+            source: std::num::ParseIntError,
+        },
+        #[error("Expected + or - between the terms of {0:?}")]
+        MissingOperator(String),
+        #[error("Cannot estimate a roll from zero samples")]
+        ZeroSamples,
+    }
+}
+
+static DICE: Pattern = Pattern::new(
+    r"(?x)(?P<count>\d*)(?P<dtype>(?P<kind>[du])(?P<sides>\d+|F|\{(?P<sizes>[^}]*)\}))
+        (w\[(?P<weights>[^\]]*)\])?
+        (r\[(?P<reroll>[^\]]*)\])?
+        (?P<cursed>-max)?
+        ((?P<explode>!h?)(c(?P<cap>\d+))?)?
+        ((?P<per_first>[+-]per\d+)\s*)?
+        (?P<keep>klnz|[kd][hl]\d*|-l\d+)?
+        (?P<per>[+-]per\d+)?\+?",
+    scan::dice,
+);
+
+/// Stops a die that always rolls its maximum, such as `1d1!`, exploding forever.
+pub const MAX_EXPLOSIONS: u32 = 100;
+
+/// The most dice a single group can roll.
+pub const MAX_DICE: u32 = 10_000;
+
+/// How many rolls estimate the mean of `best` when its roll can't be enumerated.
+const BEST_SAMPLES: usize = 10_000;
+
+/// The most sides a die can have, so that every face fits in an `i32`.
+pub const MAX_SIDES: u32 = (i32::MAX - 1) as u32;
+
+static CONSTANTS: Pattern = Pattern::new(
+    r"(?P<sign>^|[+-])[ \t]*(?P<const>\d+)[ \t]*([+-]|$)",
+    scan::constant,
+);
+
+static MALFORMED_DIE: Pattern = Pattern::new(r"(?P<token>\d+[du])(\D|$)", scan::malformed_die);
+
+static SYMBOLS: Pattern = Pattern::new(
+    r"(?P<count>\d+)d(?P<name>[A-Z][A-Z0-9_]+)\[(?P<faces>[^\]]*)\]",
+    scan::symbols,
+);
+
+static FUNCTIONS: Pattern = Pattern::new(r"(?P<name>max|min|avg|crit|best)\(", scan::function);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Die {
+    pub sides: u32,
+    /// Relative likelihood of each face, lowest face first; `None` for a fair die.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weights: Option<Vec<f64>>,
+}
+
+impl Die {
+    pub fn new(sides: u32) -> Self {
+        Die {
+            sides,
+            weights: None,
+        }
+    }
+
+    /// A loaded die, such as `1d6w[1,1,1,1,1,3]` where a 6 is three times as likely.
+    pub fn weighted(sides: u32, weights: Vec<f64>) -> Result<Self> {
+        let die = Die {
+            sides,
+            weights: Some(weights),
+        };
+        die.validate()?;
+        Ok(die)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.sides == 0 {
+            return Err(Error::NoSides.into());
+        }
+        if self.sides > MAX_SIDES {
+            return Err(Error::TooManySides {
+                sides: self.sides,
+                max: MAX_SIDES,
+            }
+            .into());
+        }
+
+        if let Some(weights) = &self.weights {
+            let invalid = |reason: &str| Error::InvalidWeights {
+                sides: self.sides,
+                reason: reason.to_string(),
+            };
+            if weights.len() != self.sides as usize {
+                let reason = format!("expected {} weights, got {}", self.sides, weights.len());
+                return Err(invalid(&reason).into());
+            }
+            if weights.iter().any(|w| !w.is_finite() || *w < 0.0) {
+                return Err(invalid("weights must be non-negative").into());
+            }
+            if weights.iter().all(|w| *w == 0.0) {
+                return Err(invalid("at least one weight must be positive").into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The chance of rolling each face, lowest face first.
+    pub fn chances(&self) -> Vec<f64> {
+        match &self.weights {
+            Some(weights) => {
+                let total: f64 = weights.iter().sum();
+                weights.iter().map(|w| w / total).collect()
+            }
+            None => vec![1.0 / f64::from(self.sides); self.sides as usize],
+        }
+    }
+
+    /// The chance of rolling the highest face, which is what makes a die explode.
+    pub fn max_chance(&self) -> f64 {
+        match &self.weights {
+            Some(weights) => weights[weights.len() - 1] / weights.iter().sum::<f64>(),
+            None => 1.0 / f64::from(self.sides),
+        }
+    }
+
+    pub fn mean(&self) -> f64 {
+        match &self.weights {
+            Some(weights) => {
+                let faces: f64 = weights.iter().zip(1..).map(|(w, f)| w * f64::from(f)).sum();
+                faces / weights.iter().sum::<f64>()
+            }
+            None => f64::from(self.sides + 1) / 2.0,
+        }
+    }
+
+    pub fn roll<R: Rng + ?Sized>(&self, rng: &mut R) -> i32 {
+        match &self.weights {
+            Some(weights) => {
+                let index = WeightedIndex::new(weights).expect("Weights are validated on creation");
+                index.sample(rng) as i32 + 1
+            }
+            None => {
+                let sides = i32::try_from(self.sides).expect("Dice are validated to fit an i32");
+                rng.gen_range(1, sides + 1)
+            }
+        }
+    }
+}
+
+/// Which dice of a group count towards the total.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Keep {
+    Highest(u32),
+    Lowest(u32),
+    DropHighest(u32),
+    DropLowest(u32),
+    /// The single lowest die above zero, skipping dice a penalty took to zero or below;
+    /// if there are none, no die is kept.
+    LowestNonzero,
+    /// Take the lowest dice away from the sum, as in the penalty roll `3d6-l1`. This comes to
+    /// the same total as dropping them, so they're marked as not kept.
+    SubtractLowest(u32),
+}
+
+impl FromStr for Keep {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "klnz" {
+            return Ok(Keep::LowestNonzero);
+        }
+        let invalid = |source| Error::InvalidCount {
+            token: s.to_string(),
+            source,
+        };
+        if let Some(n) = s.strip_prefix("-l") {
+            return Ok(Keep::SubtractLowest(n.parse().map_err(invalid)?));
+        }
+        let n = match &s[2..] {
+            "" => 1,
+            n => n.parse().map_err(invalid)?,
+        };
+        Ok(match &s[..2] {
+            "kh" => Keep::Highest(n),
+            "kl" => Keep::Lowest(n),
+            "dh" => Keep::DropHighest(n),
+            "dl" => Keep::DropLowest(n),
+            _ => unreachable!(),
+        })
+    }
+}
+
+impl fmt::Display for Keep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Keep::Highest(n) => write!(f, "kh{}", n),
+            Keep::Lowest(n) => write!(f, "kl{}", n),
+            Keep::DropHighest(n) => write!(f, "dh{}", n),
+            Keep::DropLowest(n) => write!(f, "dl{}", n),
+            Keep::LowestNonzero => write!(f, "klnz"),
+            Keep::SubtractLowest(n) => write!(f, "-l{}", n),
+        }
+    }
+}
+
+impl Keep {
+    /// Returns, for each score, whether it is kept.
+    pub fn apply(&self, scores: &[i32]) -> Vec<bool> {
+        if *self == Keep::LowestNonzero {
+            let lowest = scores.iter().filter(|n| **n > 0).min();
+            let index = lowest.and_then(|m| scores.iter().position(|n| n == m));
+            return (0..scores.len()).map(|i| Some(i) == index).collect();
+        }
+
+        let mut order: Vec<usize> = (0..scores.len()).collect();
+        order.sort_by_key(|&i| scores[i]);
+
+        let len = scores.len();
+        let (lowest, highest) = match *self {
+            Keep::Highest(n) => (false, (n as usize).min(len)),
+            Keep::Lowest(n) => (true, (n as usize).min(len)),
+            Keep::DropHighest(n) => (true, len.saturating_sub(n as usize)),
+            Keep::DropLowest(n) | Keep::SubtractLowest(n) => {
+                (false, len.saturating_sub(n as usize))
+            }
+            Keep::LowestNonzero => unreachable!(),
+        };
+
+        let mut kept = vec![false; len];
+        let selected: Vec<usize> = if lowest {
+            order.into_iter().take(highest).collect()
+        } else {
+            order.into_iter().rev().take(highest).collect()
+        };
+        for i in selected {
+            kept[i] = true;
+        }
+        kept
+    }
+}
+
+/// Which dice roll again and add when they show their highest face.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Explode {
+    /// Every die, as in `3d6!`.
+    Each,
+    /// Only the single highest die of the group, as in `3d6!h`.
+    Highest,
+}
+
+impl From<&str> for Explode {
+    fn from(s: &str) -> Self {
+        match s {
+            "!" => Explode::Each,
+            "!h" => Explode::Highest,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// A set of identical dice. Each die is rolled (and rerolled), then exploded, then given its
+/// per-die bonus, and keep/drop picks from those final values: `4d6!kh3` keeps the three
+/// highest totals after exploding, and `3d6+per1 kh2` the two highest after adding 1 to
+/// each. The kept dice are then summed, multiplied on a crit, and only then are flat
+/// modifiers added.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DiceGroup {
+    pub count: u32,
+    pub die: Die,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep: Option<Keep>,
+    /// Reroll duplicates so every die shows a different face, as in `3u6`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unique: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explode: Option<Explode>,
+    /// The most an exploding die can add up to, as in `3d6!c18`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explode_cap: Option<i32>,
+    /// Faces that are rerolled once, as in `2d6r[1,5]`. The new roll stands even if it shows
+    /// one of these faces again.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reroll: Vec<i32>,
+    /// The highest face can never come up, as in `3d6-max`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cursed: bool,
+    /// Added to every die before keep/drop, as in `8d8+per2`.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub per_die: i32,
+    /// Fudge dice, as in `4dF`: rolled as d3s but showing -1, 0 or +1.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub fudge: bool,
+    /// The least each die can count for once its per-die bonus is added, so that a penalty
+    /// can't take it any lower; applied before keep/drop.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub floor: Option<i32>,
+    /// The most each die can count for, applied after the floor.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ceiling: Option<i32>,
+    /// Subtracted from the total rather than added, as the `1d4` in `1d6-1d4`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub negative: bool,
+}
+
+fn is_zero(n: &i32) -> bool {
+    *n == 0
+}
+
+impl DiceGroup {
+    pub fn new(count: u32, die: Die) -> Self {
+        DiceGroup {
+            count,
+            die,
+            keep: None,
+            unique: false,
+            explode: None,
+            explode_cap: None,
+            reroll: Vec::new(),
+            cursed: false,
+            per_die: 0,
+            fudge: false,
+            floor: None,
+            ceiling: None,
+            negative: false,
+        }
+    }
+
+    /// Fudge dice showing -1, 0 or +1.
+    pub fn fudge(count: u32) -> Self {
+        DiceGroup {
+            fudge: true,
+            ..DiceGroup::new(count, Die::new(3))
+        }
+    }
+
+    /// What is added to every die's face: its per-die bonus, and for Fudge dice the shift
+    /// from 1..=3 down to -1..=1.
+    pub fn offset(&self) -> i32 {
+        match self.fudge {
+            true => self.per_die - 2,
+            false => self.per_die,
+        }
+    }
+
+    /// A die's value from its face, or its total once exploded: the offset is added, then
+    /// the floor and ceiling applied.
+    pub fn adjust(&self, n: i32) -> i32 {
+        let n = n + self.offset();
+        let n = self.floor.map_or(n, |floor| n.max(floor));
+        self.ceiling.map_or(n, |ceiling| n.min(ceiling))
+    }
+
+    /// The die actually rolled: a cursed die loses its highest face.
+    fn faces(&self) -> Die {
+        match (self.cursed, &self.die.weights) {
+            (false, _) => self.die.clone(),
+            (true, None) => Die::new(self.die.sides - 1),
+            (true, Some(weights)) => Die {
+                sides: self.die.sides - 1,
+                weights: Some(weights[..weights.len() - 1].to_vec()),
+            },
+        }
+    }
+
+    /// The chance of each face on a die's first roll, once any reroll is taken into account.
+    pub fn chances(&self) -> Vec<f64> {
+        let mut chances = self.faces().chances();
+        if self.cursed {
+            chances.push(0.0);
+        }
+        let rerolled: f64 = (1..)
+            .zip(&chances)
+            .filter(|(face, _)| self.reroll.contains(face))
+            .map(|(_, p)| p)
+            .sum();
+        (1..)
+            .zip(&chances)
+            .map(|(face, p)| match self.reroll.contains(&face) {
+                true => rerolled * p,
+                false => p + rerolled * p,
+            })
+            .collect()
+    }
+
+    /// The mean total of the kept dice, allowing for explosions (ignoring `MAX_EXPLOSIONS`,
+    /// and any ceiling on what an exploded die adds).
+    pub fn mean(&self) -> f64 {
+        let mean = self.die.mean();
+        let again = self.die.max_chance();
+        // Rerolls only apply to a die's first roll, not to the extra rolls from exploding.
+        let chances = self.chances();
+        let first: f64 = (1..)
+            .zip(&chances)
+            .map(|(face, p)| f64::from(self.adjust(face) - self.offset()) * p)
+            .sum();
+        let first_max = chances[chances.len() - 1];
+        let extra = match (self.explode_cap, again < 1.0) {
+            (Some(cap), _) => self.capped_extra(cap),
+            (None, true) => mean / (1.0 - again),
+            (None, false) => mean * f64::from(MAX_EXPLOSIONS),
+        };
+        let kept = f64::from(self.kept_count());
+        let bonus = kept * f64::from(self.offset());
+
+        let mean = bonus
+            + match self.explode {
+                Some(Explode::Each) => kept * (first + first_max * extra),
+                Some(Explode::Highest) => {
+                    let any_max = 1.0 - (1.0 - first_max).powi(self.count as i32);
+                    kept * first + any_max * extra
+                }
+                None => kept * first,
+            };
+        match self.negative {
+            true => -mean,
+            false => mean,
+        }
+    }
+
+    /// The mean amount exploding adds to a die that showed its highest face, when the die's
+    /// total is capped at `cap`.
+    fn capped_extra(&self, cap: i32) -> f64 {
+        let max = self.die.sides as i32;
+        let chances = self.die.chances();
+        // Work back from the last running total that can still explode, each state being the
+        // expected final value of a die that has just rolled its highest face.
+        let mut totals: Vec<i32> = (1..).map(|n| n * max).take_while(|s| *s < cap).collect();
+        let mut expected = f64::from(cap);
+        while let Some(sum) = totals.pop() {
+            expected = (1..max)
+                .zip(&chances)
+                .map(|(face, p)| p * f64::from((sum + face).min(cap)))
+                .sum::<f64>()
+                + chances[chances.len() - 1]
+                    * match sum + max < cap {
+                        true => expected,
+                        false => f64::from(cap),
+                    };
+        }
+        match cap > max {
+            true => expected - f64::from(max),
+            false => 0.0,
+        }
+    }
+
+    /// The number of dice that count towards the total once keep/drop is applied.
+    pub fn kept_count(&self) -> u32 {
+        match self.keep {
+            Some(Keep::Highest(n)) | Some(Keep::Lowest(n)) => n.min(self.count),
+            Some(Keep::DropHighest(n))
+            | Some(Keep::DropLowest(n))
+            | Some(Keep::SubtractLowest(n)) => self.count.saturating_sub(n),
+            Some(Keep::LowestNonzero) => self.count.min(1),
+            None => self.count,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.die.validate()?;
+        if let (Some(low), Some(high)) = (self.floor, self.ceiling) {
+            if low > high {
+                return Err(Error::InvalidClamp { low, high }.into());
+            }
+        }
+        if self.count > MAX_DICE {
+            return Err(Error::TooManyDice {
+                count: self.count,
+                max: MAX_DICE,
+            }
+            .into());
+        }
+        if self.cursed {
+            self.faces()
+                .validate()
+                .map_err(|_| Error::CursedDie(self.die.sides))?;
+        }
+
+        if let Some(keep) = &self.keep {
+            let valid = match *keep {
+                Keep::Highest(n) | Keep::Lowest(n) => n >= 1 && n <= self.count,
+                Keep::DropHighest(n) | Keep::DropLowest(n) | Keep::SubtractLowest(n) => {
+                    n < self.count
+                }
+                Keep::LowestNonzero => self.count >= 1,
+            };
+            if !valid {
+                return Err(Error::InvalidKeep {
+                    keep: keep.to_string(),
+                    count: self.count,
+                }
+                .into());
+            }
+        }
+
+        if let Some(cap) = self.explode_cap {
+            if cap < self.die.sides as i32 {
+                return Err(Error::InvalidExplodeCap {
+                    cap,
+                    sides: self.die.sides,
+                }
+                .into());
+            }
+        }
+
+        if self.unique && self.count > self.die.sides - self.cursed as u32 {
+            return Err(Error::ImpossibleUnique {
+                count: self.count,
+                sides: self.die.sides,
+            }
+            .into());
+        }
+
+        check_range(self, self.largest())
+    }
+
+    /// The furthest from zero the group's total can be, with room to spare.
+    fn largest(&self) -> i64 {
+        let sides = i64::from(self.die.sides);
+        let face = match (&self.explode, self.explode_cap) {
+            (None, _) => sides,
+            (Some(_), Some(cap)) => i64::from(cap).abs(),
+            (Some(_), None) => sides * i64::from(MAX_EXPLOSIONS + 1),
+        };
+        let die = (face + i64::from(self.offset()).abs())
+            .max(self.floor.map_or(0, |n| i64::from(n).abs()))
+            .max(self.ceiling.map_or(0, |n| i64::from(n).abs()));
+        i64::from(self.count) * die
+    }
+
+    /// Rolls a die once, rerolling it a single time if it shows one of the `reroll` faces.
+    fn roll_first<R: Rng + ?Sized>(&self, rng: &mut R) -> i32 {
+        let die = self.faces();
+        match die.roll(rng) {
+            n if self.reroll.contains(&n) => die.roll(rng),
+            n => n,
+        }
+    }
+
+    fn roll_unique<R: Rng + ?Sized>(&self, rng: &mut R) -> Vec<i32> {
+        let mut scores = Vec::with_capacity(self.count as usize);
+        while scores.len() < self.count as usize {
+            let n = self.roll_first(rng);
+            if !scores.contains(&n) {
+                scores.push(n);
+            }
+        }
+        scores
+    }
+
+    /// Adds further rolls to a die that showed its highest face, returning the number added.
+    fn explode_die<R: Rng + ?Sized>(&self, first: i32, rng: &mut R) -> (i32, u32) {
+        let max = self.die.sides as i32;
+        let cap = self.explode_cap.unwrap_or(i32::MAX);
+        let (mut value, mut last, mut explosions) = (first, first, 0);
+        while last == max && value < cap && explosions < MAX_EXPLOSIONS {
+            last = self.die.roll(rng);
+            value += last;
+            explosions += 1;
+        }
+        (value.min(cap), explosions)
+    }
+
+    pub fn cast<R: Rng + ?Sized>(&self, rng: &mut R) -> Result<GroupResult> {
+        self.validate()?;
+        Ok(self.roll(rng))
+    }
+
+    /// Rolls a group that has already been validated.
+    fn roll<R: Rng + ?Sized>(&self, rng: &mut R) -> GroupResult {
+        let firsts: Vec<i32> = match self.unique {
+            true => self.roll_unique(rng),
+            false => (0..self.count).map(|_| self.roll_first(rng)).collect(),
+        };
+        let highest = firsts
+            .iter()
+            .max()
+            .and_then(|m| firsts.iter().position(|n| n == m));
+        let (scores, explosions): (Vec<i32>, Vec<u32>) = firsts
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(i, n)| match self.explode {
+                Some(Explode::Each) => self.explode_die(n, rng),
+                Some(Explode::Highest) if Some(i) == highest => self.explode_die(n, rng),
+                _ => (n, 0),
+            })
+            .map(|(n, explosions)| (self.adjust(n), explosions))
+            .unzip();
+        let kept = match &self.keep {
+            Some(keep) => keep.apply(&scores),
+            None => vec![true; scores.len()],
+        };
+
+        GroupResult {
+            die: self.die.clone(),
+            negative: self.negative,
+            rolls: scores
+                .into_iter()
+                .zip(firsts)
+                .zip(kept)
+                .zip(explosions)
+                .map(|(((value, natural), kept), explosions)| DieResult {
+                    value,
+                    natural,
+                    kept,
+                    explosions,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl DiceGroup {
+    /// The group cut down to `MAX_DICE` dice that don't explode, or `None` if it's within
+    /// the limit already.
+    fn capped(&self) -> Option<DiceGroup> {
+        if self.count <= MAX_DICE {
+            return None;
+        }
+        let keep = self.keep.as_ref().map(|keep| match *keep {
+            Keep::Highest(n) => Keep::Highest(n.min(MAX_DICE)),
+            Keep::Lowest(n) => Keep::Lowest(n.min(MAX_DICE)),
+            Keep::DropHighest(n) => Keep::DropHighest(n.min(MAX_DICE - 1)),
+            Keep::DropLowest(n) => Keep::DropLowest(n.min(MAX_DICE - 1)),
+            Keep::SubtractLowest(n) => Keep::SubtractLowest(n.min(MAX_DICE - 1)),
+            Keep::LowestNonzero => Keep::LowestNonzero,
+        });
+        Some(DiceGroup {
+            count: MAX_DICE,
+            keep,
+            explode: None,
+            explode_cap: None,
+            ..self.clone()
+        })
+    }
+
+    /// Whether the two groups roll the same way, so that they give the same results as a
+    /// single group of their combined dice.
+    fn merges_with(&self, other: &DiceGroup) -> bool {
+        self.keep.is_none()
+            && !self.unique
+            && self.explode != Some(Explode::Highest)
+            && *other
+                == DiceGroup {
+                    count: other.count,
+                    ..self.clone()
+                }
+    }
+}
+
+impl fmt::Display for DiceGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.negative {
+            write!(f, "-")?;
+        }
+        let kind = if self.unique { "u" } else { "d" };
+        match self.fudge {
+            true => write!(f, "{}{}F", self.count, kind)?,
+            false => write!(f, "{}{}{}", self.count, kind, self.die.sides)?,
+        }
+        if let Some(weights) = &self.die.weights {
+            let weights: Vec<String> = weights.iter().map(|w| w.to_string()).collect();
+            write!(f, "w[{}]", weights.join(","))?;
+        }
+        if !self.reroll.is_empty() {
+            let faces: Vec<String> = self.reroll.iter().map(i32::to_string).collect();
+            write!(f, "r[{}]", faces.join(","))?;
+        }
+        if self.cursed {
+            write!(f, "-max")?;
+        }
+        match self.explode {
+            Some(Explode::Each) => write!(f, "!")?,
+            Some(Explode::Highest) => write!(f, "!h")?,
+            None => {}
+        }
+        if let Some(cap) = self.explode_cap {
+            write!(f, "c{}", cap)?;
+        }
+        if let Some(keep) = &self.keep {
+            write!(f, "{}", keep)?;
+        }
+        match self.per_die {
+            0 => {}
+            n if n > 0 => write!(f, "+per{}", n)?,
+            n => write!(f, "-per{}", -n)?,
+        }
+        // There's no notation for a floor or ceiling, so only the alternate form, used for
+        // the canonical string, shows them.
+        if f.alternate() {
+            if let Some(floor) = self.floor {
+                write!(f, "floor{}", floor)?;
+            }
+            if let Some(ceiling) = self.ceiling {
+                write!(f, "ceil{}", ceiling)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats `value`, passing on the alternate flag of `f`.
+fn format_like(f: &fmt::Formatter, value: &dyn fmt::Display) -> String {
+    match f.alternate() {
+        true => format!("{:#}", value),
+        false => value.to_string(),
+    }
+}
+
+/// Dice whose faces show named symbols rather than numbers, as in
+/// `2dFFG[blank,success,success,advantage]`. The name is upper case, each face is equally
+/// likely, and the symbols are tallied rather than added to the total.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SymbolDice {
+    pub count: u32,
+    pub name: String,
+    pub faces: Vec<String>,
+}
+
+impl SymbolDice {
+    pub fn validate(&self) -> Result<()> {
+        match self.faces.is_empty() {
+            true => Err(Error::NoSides.into()),
+            false => Ok(()),
+        }
+    }
+
+    fn roll<R: Rng + ?Sized>(&self, rng: &mut R) -> SymbolResult {
+        SymbolResult {
+            name: self.name.clone(),
+            faces: (0..self.count)
+                .map(|_| self.faces[rng.gen_range(0, self.faces.len())].clone())
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for SymbolDice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}d{}[{}]", self.count, self.name, self.faces.join(","))
+    }
+}
+
+/// A function-call form such as `max(1d20,1d12)`, each argument being evaluated separately.
+/// `avg(2d8)` stands in for the floor of its argument's mean, without rolling it.
+/// `crit(1d8)` marks the dice a crit multiplies: once any part of a roll is tagged, the rest
+/// of it is rolled as normal on a crit. `best(3,4d6dl1)` rolls its expression three times and
+/// keeps the highest total.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Function {
+    Max(Vec<Roll>),
+    Min(Vec<Roll>),
+    Avg(Box<Roll>),
+    Crit(Box<Roll>),
+    Best { times: u32, roll: Box<Roll> },
+}
+
+impl Function {
+    fn new(name: &str, args: Vec<&str>) -> Result<Self> {
+        let got = args.len();
+        let wrong_count = |expected| Error::ArgumentCount {
+            name: name.to_string(),
+            expected,
+            got,
+        };
+        if name == "best" {
+            if args.len() != 2 {
+                return Err(wrong_count(2).into());
+            }
+            let times = args[0].trim();
+            return Ok(Function::Best {
+                times: times.parse().map_err(|_| Error::InvalidArgument {
+                    name: name.to_string(),
+                    arg: times.to_string(),
+                })?,
+                roll: Box::new(parse(args[1])?),
+            });
+        }
+
+        let mut args = args.into_iter().map(parse).collect::<Result<Vec<Roll>>>()?;
+        Ok(match name {
+            "max" => Function::Max(args),
+            "min" => Function::Min(args),
+            "avg" if args.len() == 1 => Function::Avg(Box::new(args.remove(0))),
+            "crit" if args.len() == 1 => Function::Crit(Box::new(args.remove(0))),
+            "avg" | "crit" => return Err(wrong_count(1).into()),
+            _ => unreachable!(),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Function::Max(_) => "max",
+            Function::Min(_) => "min",
+            Function::Avg(_) => "avg",
+            Function::Crit(_) => "crit",
+            Function::Best { .. } => "best",
+        }
+    }
+
+    pub fn is_crit(&self) -> bool {
+        matches!(self, Function::Crit(_))
+    }
+
+    pub fn mean(&self, crit: i32) -> f64 {
+        let means = self.args().iter().map(|a| a.mean(crit));
+        match self {
+            Function::Max(_) => means.fold(f64::MIN, f64::max),
+            Function::Min(_) => means.fold(f64::MAX, f64::min),
+            Function::Avg(roll) => f64::from(roll.average(crit)),
+            Function::Crit(roll) => roll.mean(crit),
+            // The best of several rolls has no simple formula, so work it out from the exact
+            // distribution. Rolls that can't be enumerated, such as exploding dice, are
+            // sampled with a fixed seed so the estimate doesn't change between calls.
+            Function::Best { .. } => match self.distribution(crit, analysis::DEFAULT_FACES_LIMIT) {
+                Ok(dist) => dist.mean(),
+                Err(_) => {
+                    let roll = Roll {
+                        functions: vec![self.clone()],
+                        ..Roll::new()
+                    };
+                    let (_, mut rng) = seeded_rng(Some(0));
+                    roll.sample_distribution(crit, &mut rng, BEST_SAMPLES)
+                        .map_or_else(|_| self.args()[0].mean(crit), |dist| dist.mean())
+                }
+            },
+        }
+    }
+
+    pub fn args(&self) -> &[Roll] {
+        match self {
+            Function::Max(args) | Function::Min(args) => args,
+            Function::Avg(roll) | Function::Crit(roll) | Function::Best { roll, .. } => {
+                std::slice::from_ref(roll)
+            }
+        }
+    }
+
+    fn args_mut(&mut self) -> &mut [Roll] {
+        match self {
+            Function::Max(args) | Function::Min(args) => args,
+            Function::Avg(roll) | Function::Crit(roll) | Function::Best { roll, .. } => {
+                std::slice::from_mut(roll)
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.args().is_empty() {
+            return Err(Error::EmptyFunction(self.name().to_string()).into());
+        }
+        if let Function::Best { times: 0, .. } = self {
+            return Err(Error::InvalidArgument {
+                name: self.name().to_string(),
+                arg: "0".to_string(),
+            }
+            .into());
+        }
+        for arg in self.args() {
+            arg.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn cast<R: Rng + ?Sized>(&self, crit: i32, rng: &mut R) -> Result<FunctionResult> {
+        self.validate()?;
+        check_range(self, self.largest(crit))?;
+        Ok(self.roll(crit, rng))
+    }
+
+    fn largest(&self, crit: i32) -> i64 {
+        self.args()
+            .iter()
+            .map(|a| a.largest(crit))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Evaluates a function whose arguments have already been validated.
+    fn roll<R: Rng + ?Sized>(&self, crit: i32, rng: &mut R) -> FunctionResult {
+        if let Function::Avg(roll) = self {
+            return FunctionResult {
+                name: self.name().to_string(),
+                values: Vec::new(),
+                chosen: 0,
+                value: roll.average(crit),
+            };
+        }
+
+        let highest = !matches!(self, Function::Min(_));
+        let values: Vec<RollResult> = match self {
+            Function::Best { times, roll } => (0..*times).map(|_| roll.roll(crit, rng)).collect(),
+            _ => self.args().iter().map(|a| a.roll(crit, rng)).collect(),
+        };
+
+        let mut chosen = 0;
+        for (i, v) in values.iter().enumerate() {
+            let best = values[chosen].total;
+            if (highest && v.total > best) || (!highest && v.total < best) {
+                chosen = i;
+            }
+        }
+
+        FunctionResult {
+            name: self.name().to_string(),
+            value: values[chosen].total,
+            values,
+            chosen,
+        }
+    }
+}
+
+impl Function {
+    fn canonical(&self) -> Function {
+        let canonical = |roll: &Roll| Box::new(roll.canonical());
+        match self {
+            Function::Max(args) | Function::Min(args) => {
+                let mut args: Vec<Roll> = args.iter().map(Roll::canonical).collect();
+                args.sort_by_cached_key(|a| format!("{:#}", a));
+                match self {
+                    Function::Max(_) => Function::Max(args),
+                    _ => Function::Min(args),
+                }
+            }
+            Function::Avg(roll) => Function::Avg(canonical(roll)),
+            Function::Crit(roll) => Function::Crit(canonical(roll)),
+            Function::Best { times, roll } => Function::Best {
+                times: *times,
+                roll: canonical(roll),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut args: Vec<String> = self.args().iter().map(|a| format_like(f, a)).collect();
+        if let Function::Best { times, .. } = self {
+            args.insert(0, times.to_string());
+        }
+        write!(f, "{}({})", self.name(), args.join(","))
+    }
+}
+
+/// A parsed expression.
+///
+/// It serializes to JSON as, for `4d6dl1+1d8!+2`:
+///
+/// ```json
+/// {
+///   "dice": [
+///     { "count": 4, "die": { "sides": 6 }, "keep": { "drop_lowest": 1 } },
+///     { "count": 1, "die": { "sides": 8 }, "explode": "each" }
+///   ],
+///   "constants": [2],
+///   "functions": []
+/// }
+/// ```
+///
+/// Options that aren't set (`keep`, `explode`, `unique`, `weights`) are left out, and nested
+/// rolls in `functions` follow the same shape, as in `{ "max": [ { "dice": ... } ] }`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Roll {
+    pub dice: Vec<DiceGroup>,
+    #[serde(default)]
+    pub constants: Vec<i32>,
+    #[serde(default)]
+    pub functions: Vec<Function>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub symbols: Vec<SymbolDice>,
+}
+
+impl Roll {
+    pub fn new() -> Self {
+        Roll {
+            dice: Vec::new(),
+            constants: Vec::new(),
+            functions: Vec::new(),
+            symbols: Vec::new(),
+        }
+    }
+
+    pub fn builder() -> RollBuilder {
+        RollBuilder::default()
+    }
+
+    /// Adds everything in `other` to this roll, as when layering a buff onto an attack: its
+    /// dice, modifiers and function calls follow this roll's own.
+    pub fn merge(mut self, other: Roll) -> Roll {
+        let Roll {
+            dice,
+            constants,
+            functions,
+            symbols,
+        } = other;
+        self.dice.extend(dice);
+        self.constants.extend(constants);
+        self.functions.extend(functions);
+        self.symbols.extend(symbols);
+        self
+    }
+
+    /// Builds a roll from `(count, die)` groups and flat modifiers, such as one that was
+    /// stored as structured data, without going through the parser.
+    pub fn from_parts(dice: Vec<(u32, Die)>, modifiers: Vec<i32>) -> Result<Self> {
+        let roll = Roll {
+            dice: dice
+                .into_iter()
+                .map(|(count, die)| DiceGroup::new(count, die))
+                .collect(),
+            constants: modifiers,
+            ..Roll::new()
+        };
+        roll.validate()?;
+        Ok(roll)
+    }
+
+    /// The mean total, as a damage-per-round calculator would want it.
+    ///
+    /// It's exact except for kept/dropped and unique dice, which are averaged as if each kept
+    /// die were rolled alone (so `4d6dl1` gives 10.5 rather than about 12.24); for those,
+    /// `analysis::Distribution::mean` gives the exact figure.
+    pub fn expected_value(&self) -> f64 {
+        self.mean(1)
+    }
+
+    /// The mean total when rolled with `crit` as the crit multiplier.
+    pub fn expected_value_with_crit(&self, crit: i32) -> f64 {
+        self.mean(crit)
+    }
+
+    fn mean(&self, crit: i32) -> f64 {
+        let dice: f64 = self.dice.iter().map(DiceGroup::mean).sum();
+        let called: f64 = self
+            .functions
+            .iter()
+            .map(|f| f.mean(self.function_crit(f, crit)))
+            .sum();
+        let constant: i32 = self.constants.iter().sum();
+
+        (dice * f64::from(self.untagged_crit(crit))) + called + f64::from(constant)
+    }
+
+    /// The multiplier for the dice outside any `crit(...)`, which only see the crit when
+    /// nothing in the roll is tagged.
+    pub fn untagged_crit(&self, crit: i32) -> i32 {
+        match self.functions.iter().any(Function::is_crit) {
+            true => 1,
+            false => crit,
+        }
+    }
+
+    /// The multiplier passed on to one of the roll's functions.
+    pub fn function_crit(&self, function: &Function, crit: i32) -> i32 {
+        match function.is_crit() {
+            true => crit,
+            false => self.untagged_crit(crit),
+        }
+    }
+
+    /// The average total as printed in monster stat blocks: the floor of the mean.
+    ///
+    /// Kept dice are averaged as if they were rolled alone, and `max`/`min` take the
+    /// larger/smaller of their arguments' averages, so those cases are approximate.
+    pub fn average(&self, crit: i32) -> i32 {
+        self.mean(crit).floor() as i32
+    }
+
+    /// Checks the roll makes sense before casting it; `cast` calls this itself.
+    pub fn validate(&self) -> Result<()> {
+        if self.dice.is_empty()
+            && self.constants.is_empty()
+            && self.functions.is_empty()
+            && self.symbols.is_empty()
+        {
+            return Err(Error::EmptyRoll.into());
+        }
+
+        for group in &self.dice {
+            group.validate()?;
+        }
+
+        for symbols in &self.symbols {
+            symbols.validate()?;
+        }
+
+        for function in &self.functions {
+            function.validate()?;
+        }
+
+        check_range(self, self.largest(1))
+    }
+
+    pub fn cast<R: Rng + ?Sized>(&self, crit: i32, rng: &mut R) -> Result<RollResult> {
+        self.validate()?;
+        check_range(self, self.largest(crit))?;
+        Ok(self.roll(crit, rng))
+    }
+
+    /// The furthest from zero the total can be when rolled with `crit`, with room to spare.
+    fn largest(&self, crit: i32) -> i64 {
+        let dice: i64 = self.dice.iter().map(DiceGroup::largest).sum();
+        let called: i64 = self
+            .functions
+            .iter()
+            .map(|f| f.largest(self.function_crit(f, crit)))
+            .sum();
+        let constant: i64 = self.constants.iter().map(|c| i64::from(*c).abs()).sum();
+        dice * i64::from(self.untagged_crit(crit)).abs() + called + constant
+    }
+
+    /// A copy of the roll in which no die, even within a function, counts for less than
+    /// `floor`.
+    pub fn with_floor(&self, floor: i32) -> Roll {
+        let mut roll = self.clone();
+        roll.each_group(&mut |group| group.floor = Some(floor));
+        roll
+    }
+
+    /// A copy of the roll in which every die, even within a function, counts for somewhere
+    /// in `range`. A floor already set is only ever raised.
+    pub fn with_clamp(&self, range: RangeInclusive<i32>) -> Roll {
+        let mut roll = self.clone();
+        roll.each_group(&mut |group| {
+            group.floor = Some(
+                group
+                    .floor
+                    .map_or(*range.start(), |f| f.max(*range.start())),
+            );
+            group.ceiling = Some(*range.end());
+        });
+        roll
+    }
+
+    /// Calls `f` on every dice group, including those in function arguments.
+    fn each_group(&mut self, f: &mut dyn FnMut(&mut DiceGroup)) {
+        for group in &mut self.dice {
+            f(group);
+        }
+        for function in &mut self.functions {
+            for arg in function.args_mut() {
+                arg.each_group(f);
+            }
+        }
+    }
+
+    /// A copy of the roll that can be cast even if some of its groups are too big: each is
+    /// capped at `MAX_DICE` dice and stops exploding. Also returns a warning for every group
+    /// that was cut down.
+    pub fn best_effort(&self) -> (Roll, Vec<String>) {
+        let mut roll = self.clone();
+        let mut warnings = Vec::new();
+        roll.each_group(&mut |group| {
+            if let Some(capped) = group.capped() {
+                warnings.push(format!(
+                    "Rolling {} instead of {}: at most {} dice can be rolled at once",
+                    capped, group, MAX_DICE
+                ));
+                *group = capped;
+            }
+        });
+        (roll, warnings)
+    }
+
+    /// The notation for the roll with like dice combined, terms sorted and modifiers summed,
+    /// so that rolls which differ only in how they are written, such as `d6+1d6+2+3` and
+    /// `2d6+5`, give the same string. Rolls with different outcomes give different strings,
+    /// so the string also shows each group's floor and ceiling, which have no notation.
+    pub fn to_canonical(&self) -> String {
+        format!("{:#}", self.canonical())
+    }
+
+    fn canonical(&self) -> Roll {
+        let mut dice: Vec<DiceGroup> = Vec::new();
+        for group in self.dice.iter().filter(|g| g.count > 0) {
+            match dice.iter_mut().find(|d| d.merges_with(group)) {
+                Some(merged) => merged.count += group.count,
+                None => dice.push(group.clone()),
+            }
+        }
+        dice.sort_by_cached_key(|g| (std::cmp::Reverse(g.die.sides), format!("{:#}", g)));
+
+        let mut symbols: Vec<SymbolDice> = Vec::new();
+        for set in self.symbols.iter().filter(|s| s.count > 0) {
+            let mut faces = set.faces.clone();
+            faces.sort();
+            match symbols
+                .iter_mut()
+                .find(|s| s.name == set.name && s.faces == faces)
+            {
+                Some(merged) => merged.count += set.count,
+                None => symbols.push(SymbolDice {
+                    faces,
+                    ..set.clone()
+                }),
+            }
+        }
+        symbols.sort_by_cached_key(SymbolDice::to_string);
+
+        let mut functions: Vec<Function> = self.functions.iter().map(Function::canonical).collect();
+        functions.sort_by_cached_key(|f| format!("{:#}", f));
+
+        let constant: i32 = self.constants.iter().sum();
+        let empty = dice.is_empty() && symbols.is_empty() && functions.is_empty();
+        Roll {
+            dice,
+            constants: match constant == 0 && !empty {
+                true => Vec::new(),
+                false => vec![constant],
+            },
+            functions,
+            symbols,
+        }
+    }
+
+    /// Casts the roll `n` times, validating it once and reusing the one RNG throughout.
+    pub fn cast_n<R: Rng + ?Sized>(
+        &self,
+        n: usize,
+        crit: i32,
+        rng: &mut R,
+    ) -> Result<Vec<RollResult>> {
+        self.validate()?;
+        check_range(self, self.largest(crit))?;
+        Ok((0..n).map(|_| self.roll(crit, rng)).collect())
+    }
+
+    /// Casts a roll that has already been validated.
+    fn roll<R: Rng + ?Sized>(&self, crit: i32, rng: &mut R) -> RollResult {
+        let groups: Vec<GroupResult> = self.dice.iter().map(|g| g.roll(rng)).collect();
+        let functions: Vec<FunctionResult> = self
+            .functions
+            .iter()
+            .map(|f| f.roll(self.function_crit(f, crit), rng))
+            .collect();
+        let symbols = self.symbols.iter().map(|s| s.roll(rng)).collect();
+
+        let dice: i32 = groups.iter().map(GroupResult::subtotal).sum();
+        let called: i32 = functions.iter().map(|f| f.value).sum();
+        let modifier_total: i32 = self.constants.iter().sum();
+
+        RollResult {
+            groups,
+            functions,
+            constants: self.constants.clone(),
+            modifier_total,
+            symbols,
+            total: (dice * self.untagged_crit(crit)) + called + modifier_total,
+        }
+    }
+}
+
+impl fmt::Display for Roll {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut terms: Vec<String> = self.dice.iter().map(|g| format_like(f, g)).collect();
+        terms.extend(self.symbols.iter().map(SymbolDice::to_string));
+        terms.extend(self.functions.iter().map(|func| format_like(f, func)));
+        terms.extend(self.constants.iter().map(|c| match *c < 0 {
+            true => c.to_string(),
+            false => format!("+{}", c),
+        }));
+
+        let mut out = String::new();
+        for term in terms {
+            if !out.is_empty() && !term.starts_with('+') && !term.starts_with('-') {
+                out.push('+');
+            }
+            out.push_str(&term);
+        }
+        write!(f, "{}", out)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DieResult {
+    pub value: i32,
+    /// The face the die first showed, before any explosions or per-die bonus.
+    #[serde(default)]
+    pub natural: i32,
+    pub kept: bool,
+    /// How many extra rolls exploding added to this die.
+    pub explosions: u32,
+}
+
+impl fmt::Display for DieResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.value)?;
+        if self.explosions > 0 {
+            write!(f, " (exploded {}x)", self.explosions)?;
+        }
+        match self.kept {
+            true => Ok(()),
+            false => write!(f, " (dropped)"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GroupResult {
+    pub die: Die,
+    /// Whether the group was subtracted, so that its subtotal counts against the total.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub negative: bool,
+    pub rolls: Vec<DieResult>,
+}
+
+impl GroupResult {
+    pub fn subtotal(&self) -> i32 {
+        let sum: i32 = self.rolls.iter().filter(|r| r.kept).map(|r| r.value).sum();
+        match self.negative {
+            true => -sum,
+            false => sum,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FunctionResult {
+    pub name: String,
+    pub values: Vec<RollResult>,
+    pub chosen: usize,
+    pub value: i32,
+}
+
+impl fmt::Display for FunctionResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.values.is_empty() {
+            return write!(f, "{} = {}", self.name, self.value);
+        }
+        let values: Vec<String> = self.values.iter().map(|v| v.total.to_string()).collect();
+        write!(f, "{}({}) = {}", self.name, values.join(", "), self.value)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RollResult {
+    pub groups: Vec<GroupResult>,
+    pub functions: Vec<FunctionResult>,
+    /// Each flat modifier, with its sign, as in `[3, -2]` for `1d6+3-2`.
+    pub constants: Vec<i32>,
+    pub modifier_total: i32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub symbols: Vec<SymbolResult>,
+    pub total: i32,
+}
+
+/// The faces shown by a set of symbol dice, in the order they were rolled.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SymbolResult {
+    pub name: String,
+    pub faces: Vec<String>,
+}
+
+impl RollResult {
+    fn dice(&self) -> impl Iterator<Item = &DieResult> {
+        self.groups.iter().flat_map(|g| &g.rolls)
+    }
+
+    /// The values of every kept die, in the order they were rolled.
+    pub fn values(&self) -> Vec<i32> {
+        self.dice().filter(|d| d.kept).map(|d| d.value).collect()
+    }
+
+    /// The total number of explosions across every die.
+    pub fn explosions(&self) -> u32 {
+        self.dice().map(|d| d.explosions).sum()
+    }
+
+    /// How many times each symbol came up across every set of symbol dice.
+    pub fn tally(&self) -> BTreeMap<&str, usize> {
+        let mut counts = BTreeMap::new();
+        for face in self.symbols.iter().flat_map(|s| &s.faces) {
+            *counts.entry(face.as_str()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// The same result with dice of the same die gathered into one group, largest die first,
+    /// and each group's results sorted highest first. Only the order changes.
+    pub fn group_sorted(&self) -> RollResult {
+        let mut groups: Vec<GroupResult> = Vec::new();
+        for group in &self.groups {
+            match groups.iter_mut().find(|g| g.die == group.die) {
+                Some(same) => same.rolls.extend(group.rolls.iter().cloned()),
+                None => groups.push(group.clone()),
+            }
+        }
+        groups.sort_by_key(|g| std::cmp::Reverse(g.die.sides));
+        for group in &mut groups {
+            group.rolls.sort_by_key(|r| std::cmp::Reverse(r.value));
+        }
+        RollResult {
+            groups,
+            ..self.clone()
+        }
+    }
+
+    /// Whether the total is made up of constants alone, with no dice to show.
+    pub fn is_constant(&self) -> bool {
+        self.groups.is_empty() && self.functions.is_empty() && self.symbols.is_empty()
+    }
+
+    /// A line for each set of symbol dice, then a tally of every symbol that came up.
+    pub fn symbol_lines(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .symbols
+            .iter()
+            .map(|s| format!("{}: {}", s.name, s.faces.join(", ")))
+            .collect();
+        if !self.symbols.is_empty() {
+            let tally: Vec<String> = self
+                .tally()
+                .iter()
+                .map(|(face, n)| format!("{} x{}", face, n))
+                .collect();
+            lines.push(format!("Symbols: {}", tally.join(", ")));
+        }
+        lines
+    }
+
+    /// How many dice, kept or dropped, first rolled their highest face.
+    pub fn max_count(&self) -> usize {
+        self.groups
+            .iter()
+            .map(|g| {
+                let max = g.die.sides as i32;
+                g.rolls.iter().filter(|r| r.natural == max).count()
+            })
+            .sum()
+    }
+
+    /// How many dice, kept or dropped, first rolled a 1.
+    pub fn min_count(&self) -> usize {
+        self.dice().filter(|d| d.natural == 1).count()
+    }
+
+    /// The most explosions any single die had in a row.
+    pub fn longest_chain(&self) -> u32 {
+        self.dice().map(|d| d.explosions).max().unwrap_or(0)
+    }
+}
+
+/// Builds a `Roll` directly, without formatting and parsing a string.
+#[derive(Clone, Debug, Default)]
+pub struct RollBuilder {
+    roll: Roll,
+}
+
+impl RollBuilder {
+    pub fn add_dice(mut self, count: u32, die: Die) -> Self {
+        self.roll.dice.push(DiceGroup::new(count, die));
+        self
+    }
+
+    /// Applies keep/drop to the most recently added dice; ignored if no dice were added yet.
+    pub fn keep(mut self, keep: Keep) -> Self {
+        if let Some(group) = self.roll.dice.last_mut() {
+            group.keep = Some(keep);
+        }
+        self
+    }
+
+    /// Makes the most recently added dice explode.
+    pub fn explode(mut self, explode: Explode) -> Self {
+        if let Some(group) = self.roll.dice.last_mut() {
+            group.explode = Some(explode);
+        }
+        self
+    }
+
+    /// Stops the most recently added dice exploding once a die's total reaches `cap`.
+    pub fn explode_cap(mut self, cap: i32) -> Self {
+        if let Some(group) = self.roll.dice.last_mut() {
+            group.explode_cap = Some(cap);
+        }
+        self
+    }
+
+    /// Adds a bonus to every one of the most recently added dice.
+    pub fn per_die(mut self, bonus: i32) -> Self {
+        if let Some(group) = self.roll.dice.last_mut() {
+            group.per_die = bonus;
+        }
+        self
+    }
+
+    /// Stops any of the most recently added dice counting for less than `floor`.
+    pub fn floor(mut self, floor: i32) -> Self {
+        if let Some(group) = self.roll.dice.last_mut() {
+            group.floor = Some(floor);
+        }
+        self
+    }
+
+    /// Stops any of the most recently added dice counting for more than `ceiling`.
+    pub fn ceiling(mut self, ceiling: i32) -> Self {
+        if let Some(group) = self.roll.dice.last_mut() {
+            group.ceiling = Some(ceiling);
+        }
+        self
+    }
+
+    /// Rerolls the most recently added dice once when they show any of `faces`.
+    pub fn reroll(mut self, faces: Vec<i32>) -> Self {
+        if let Some(group) = self.roll.dice.last_mut() {
+            group.reroll = faces;
+        }
+        self
+    }
+
+    pub fn keep_highest(self, n: u32) -> Self {
+        self.keep(Keep::Highest(n))
+    }
+
+    pub fn keep_lowest(self, n: u32) -> Self {
+        self.keep(Keep::Lowest(n))
+    }
+
+    pub fn drop_highest(self, n: u32) -> Self {
+        self.keep(Keep::DropHighest(n))
+    }
+
+    pub fn drop_lowest(self, n: u32) -> Self {
+        self.keep(Keep::DropLowest(n))
+    }
+
+    pub fn add_modifier(mut self, modifier: i32) -> Self {
+        self.roll.constants.push(modifier);
+        self
+    }
+
+    pub fn build(self) -> Roll {
+        self.roll
+    }
+}
+
+/// Creates the RNG for a session, drawing a fresh seed from entropy when none is given.
+/// The seed is returned so the same rolls can be reproduced later.
+pub fn seeded_rng(seed: Option<u64>) -> (u64, StdRng) {
+    let seed = seed.unwrap_or_else(rand::random);
+    (seed, StdRng::seed_from_u64(seed))
+}
+
+/// Which RNG a session rolls with.
+///
+/// `Thread` is the fast default: a CSPRNG local to the thread, seeded from the OS and
+/// reseeded as it goes, but with no seed to replay. `Secure` asks the OS for every number,
+/// which is much slower but keeps no RNG state in the process at all. `Seeded` is a `StdRng`
+/// from a known seed, so its rolls can be reproduced by anyone with the seed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RngKind {
+    Thread,
+    Secure,
+    Seeded,
+}
+
+impl FromStr for RngKind {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        match input {
+            "thread" => Ok(RngKind::Thread),
+            "secure" => Ok(RngKind::Secure),
+            "seeded" => Ok(RngKind::Seeded),
+            _ => Err(Error::UnknownRng(input.to_string()).into()),
+        }
+    }
+}
+
+/// Creates the RNG for a session of the given kind. Only a seeded RNG uses `seed`, drawing
+/// one from entropy when none is given, and only it returns the seed.
+pub fn session_rng(kind: RngKind, seed: Option<u64>) -> (Option<u64>, Box<dyn RngCore>) {
+    match kind {
+        RngKind::Thread => (None, Box::new(rand::thread_rng())),
+        RngKind::Secure => (None, Box::new(OsRng)),
+        RngKind::Seeded => {
+            let (seed, rng) = seeded_rng(seed);
+            (Some(seed), Box::new(rng))
+        }
+    }
+}
+
+/// Fails if a total as far from zero as `largest` wouldn't fit in an `i32`.
+fn check_range(roll: &dyn fmt::Display, largest: i64) -> Result<()> {
+    match largest > i64::from(i32::MAX) {
+        true => Err(Error::TotalTooLarge {
+            roll: roll.to_string(),
+            max: i32::MAX,
+        }
+        .into()),
+        false => Ok(()),
+    }
+}
+
+/// Rolls a single fair die, from 1 to `sides` inclusive.
+pub fn roll_die<R: Rng + ?Sized>(sides: u32, rng: &mut R) -> Result<i32> {
+    let die = Die::new(sides);
+    die.validate()?;
+    Ok(die.roll(rng))
+}
+
+/// Rolls `expr` and describes the result as the command line does by default: a line for
+/// each die, then any functions and symbol dice, a `---` separator and the total. A roll of
+/// constants alone is just its total.
+pub fn roll_verbose<R: Rng + ?Sized>(expr: &str, rng: &mut R) -> Result<String> {
+    let res = parse(expr)?.cast(1, rng)?;
+    let mut lines: Vec<String> = res
+        .groups
+        .iter()
+        .flat_map(|g| &g.rolls)
+        .map(DieResult::to_string)
+        .collect();
+    lines.extend(res.functions.iter().map(FunctionResult::to_string));
+    lines.extend(res.symbol_lines());
+    if !res.is_constant() {
+        lines.push("---".to_string());
+    }
+    lines.push(res.total.to_string());
+    Ok(lines.iter().map(|line| format!("{}\n", line)).collect())
+}
+
+/// The chance of a natural crit on a d20 when any roll of `range` or higher crits.
+pub fn crit_chance(range: u32) -> Result<f64> {
+    if !(2..=20).contains(&range) {
+        return Err(Error::InvalidCritRange(range).into());
+    }
+    Ok(f64::from(21 - range) / 20.0)
+}
+
+/// Splits the arguments of a function call on the commas that aren't nested in brackets.
+fn split_args(args: &str) -> Vec<(usize, &str)> {
+    let mut depth = 0;
+    let mut start = 0;
+    let mut out = Vec::new();
+    for (i, c) in args.char_indices() {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                out.push((start, &args[start..i]));
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    out.push((start, &args[start..]));
+    out
+}
+
+/// Splits a line of several expressions, such as `1d20+5, 2d6+3` pasted from chat, on the
+/// commas between them. Empty expressions, as from a trailing comma, are left out.
+pub fn split_exprs(input: &str) -> Vec<&str> {
+    split_args(input)
+        .into_iter()
+        .map(|(_, expr)| expr.trim())
+        .filter(|expr| !expr.is_empty())
+        .collect()
+}
+
+/// The index of the `)` closing a call whose arguments start at `from`.
+fn closing_paren(input: &str, from: usize) -> Option<usize> {
+    let mut depth = 1;
+    for (i, ch) in input[from..].char_indices() {
+        match ch {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            return Some(from + i);
+        }
+    }
+    None
+}
+
+/// Parses and removes every function call from the input, leaving the plain dice and constants.
+fn extract_functions(input: &str) -> Result<(String, Vec<Function>)> {
+    let mut rest = input.to_string();
+    let mut functions = Vec::new();
+
+    while let Some(c) = FUNCTIONS.captures(&rest) {
+        let whole = c.whole();
+        let close = closing_paren(&rest, whole.end())
+            .ok_or_else(|| Error::UnbalancedParens(input.to_string()))?;
+
+        let inner = &rest[whole.end()..close];
+        if inner.trim().is_empty() {
+            return Err(Error::EmptyFunction(c["name"].to_string()).into());
+        }
+        let args = split_args(inner).into_iter().map(|(_, arg)| arg).collect();
+        functions.push(Function::new(&c["name"], args)?);
+
+        rest.replace_range(whole.start()..=close, PLACEHOLDER);
+    }
+
+    Ok((rest, functions))
+}
+
+/// What a function call or symbol dice leaves behind in the input once taken out, so the
+/// signs around it still read as a term's. `normalize` rejects it in the input itself.
+const PLACEHOLDER: &str = "\u{fffc}";
+
+/// Checks that the terms at `spans` have one `+` or `-` between them, followed by an optional
+/// sign of the term's own, with at most a sign before the first and nothing after the last.
+/// Returns the start of
+/// each term that is subtracted, with the signs before it folded so that `--` adds.
+fn subtracted(input: &str, spans: &[Range<usize>], original: &str) -> Result<Vec<usize>> {
+    let unexpected = |c| Err(Error::UnexpectedChar(c, original.to_string()).into());
+    let mut negative = Vec::new();
+    let mut last = 0;
+    for (i, span) in spans
+        .iter()
+        .map(Some)
+        .chain(std::iter::once(None))
+        .enumerate()
+    {
+        let start = span.map_or(input.len(), |span| span.start);
+        let most = match i {
+            0 => 1,
+            _ => 2,
+        };
+        let mut signs = Vec::new();
+        for c in input[last.min(start)..start]
+            .chars()
+            .filter(|c| !c.is_whitespace())
+        {
+            match c {
+                '+' | '-' if signs.len() < most => signs.push(c),
+                c => return unexpected(c),
+            }
+        }
+        match (span, signs.last()) {
+            (Some(_), None) if i > 0 => {
+                return Err(Error::MissingOperator(original.to_string()).into())
+            }
+            (Some(span), _) => {
+                if signs.iter().filter(|&&c| c == '-').count() % 2 == 1 {
+                    negative.push(span.start);
+                }
+                last = span.end;
+            }
+            (None, Some(&c)) => return unexpected(c),
+            (None, None) => {}
+        }
+    }
+    Ok(negative)
+}
+
+/// Swaps the unicode minus signs a word processor might produce for ASCII hyphens, and
+/// rejects any other non-ASCII character rather than silently skipping it.
+fn normalize(input: &str) -> Result<String> {
+    input
+        .chars()
+        .map(|c| match c {
+            '\u{2212}' | '\u{2013}' => Ok('-'),
+            c if c.is_ascii() => Ok(c),
+            c => Err(Error::UnexpectedChar(c, input.to_string()).into()),
+        })
+        .collect()
+}
+
+impl FromStr for Roll {
+    type Err = anyhow::Error;
+
+    fn from_str(original: &str) -> Result<Self> {
+        let (input, functions) = extract_functions(&normalize(original)?)?;
+        let symbols = SYMBOLS
+            .captures_iter(&input)
+            .map(|c| {
+                Ok(SymbolDice {
+                    count: c["count"].parse()?,
+                    name: c["name"].to_string(),
+                    faces: c["faces"]
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|f| !f.is_empty())
+                        .map(String::from)
+                        .collect(),
+                })
+            })
+            .collect::<Result<Vec<SymbolDice>>>()?;
+        let input = SYMBOLS.replace_all(&input, PLACEHOLDER);
+        let input = input.as_ref();
+        if let Some(c) = MALFORMED_DIE.captures(&DICE.replace_all(input, " ")) {
+            return Err(Error::MalformedDie(c["token"].to_string()).into());
+        }
+
+        let caps: Vec<_> = DICE.captures_iter(input).collect();
+        // Each match consumes the sign after it, so resume the search straight after the number
+        // to pick up runs like `+2+3`.
+        let mut constants = Vec::new();
+        let mut at = 0;
+        while let Some(c) = CONSTANTS.captures(&input[at..]) {
+            let number = c.name("const").unwrap();
+            constants.push(at + number.start()..at + number.end());
+            at += number.end();
+        }
+        // The dice pattern takes the `+` after a group with it; leave that to the next term.
+        let mut spans: Vec<Range<usize>> = caps
+            .iter()
+            .map(|c| {
+                let whole = c.whole();
+                whole.start()..whole.end() - usize::from(whole.as_str().ends_with('+'))
+            })
+            .chain(constants.iter().cloned())
+            .chain(
+                input
+                    .match_indices(PLACEHOLDER)
+                    .map(|(i, p)| i..i + p.len()),
+            )
+            .collect();
+        spans.sort_by_key(|span| span.start);
+        let negative = subtracted(input, &spans, original)?;
+        if input
+            .match_indices(PLACEHOLDER)
+            .any(|(i, _)| negative.contains(&i))
+        {
+            // A function's total can only be added; don't let a minus before it pass unnoticed.
+            return Err(Error::UnexpectedChar('-', original.to_string()).into());
+        }
+
+        let mut roll = Roll::new();
+        roll.functions = functions;
+        roll.symbols = symbols;
+
+        for c in caps {
+            let fudge = &c["sides"] == "F";
+            let sizes: Vec<u32> = match (fudge, c.name("sizes")) {
+                (true, _) => vec![3],
+                (false, Some(sizes)) if sizes.as_str().trim().is_empty() => {
+                    return Err(Error::NoSides.into())
+                }
+                (false, Some(sizes)) => sizes
+                    .as_str()
+                    .split(',')
+                    .map(|s| s.trim().parse::<u32>())
+                    .collect::<std::result::Result<_, _>>()?,
+                (false, None) => vec![c["sides"].parse()?],
+            };
+            let weights: Option<Vec<f64>> = match c.name("weights") {
+                Some(w) => Some(
+                    w.as_str()
+                        .split(',')
+                        .map(|w| w.trim().parse::<f64>())
+                        .collect::<std::result::Result<_, _>>()?,
+                ),
+                None => None,
+            };
+            let count = match &c["count"] {
+                "" => 1,
+                count => count.parse::<u32>()?,
+            };
+            let mut group = DiceGroup::new(count, Die::new(sizes[0]));
+            group.fudge = fudge;
+            group.keep = c.name("keep").map(|k| k.as_str().parse()).transpose()?;
+            group.unique = &c["kind"] == "u";
+            group.explode = c.name("explode").map(|e| Explode::from(e.as_str()));
+            group.cursed = c.name("cursed").is_some();
+            group.negative = negative.contains(&c.whole().start());
+            if let Some(cap) = c.name("cap") {
+                group.explode_cap = Some(cap.as_str().parse()?);
+            }
+            if let Some(faces) = c.name("reroll") {
+                group.reroll = faces
+                    .as_str()
+                    .split(',')
+                    .map(|f| f.trim().parse::<i32>())
+                    .collect::<std::result::Result<_, _>>()?;
+            }
+            for per in c.name("per_first").iter().chain(&c.name("per")) {
+                let bonus = per.as_str().replacen("per", "", 1);
+                group.per_die += bonus.trim_start_matches('+').parse::<i32>()?;
+            }
+            for sides in sizes {
+                let die = match &weights {
+                    Some(weights) => Die::weighted(sides, weights.clone())?,
+                    None => Die::new(sides),
+                };
+                roll.dice.push(DiceGroup {
+                    die,
+                    ..group.clone()
+                });
+            }
+        }
+
+        for span in constants {
+            let n = input[span.clone()].parse::<i32>()?;
+            roll.constants.push(match negative.contains(&span.start) {
+                true => -n,
+                false => n,
+            });
+        }
+
+        Ok(roll)
+    }
+}
+
+/// Parses an expression; the same as `input.parse::<Roll>()`.
+pub fn parse(input: &str) -> Result<Roll> {
+    input.parse()
+}
+
+/// A parsed expression together with the tokens it was read from, for tools such as editors
+/// that highlight the parts of an expression.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Ast {
+    pub roll: Roll,
+    pub tokens: Vec<Token>,
+}
+
+/// A piece of the input and its byte range. Dice are split into their count, die and any
+/// options, and function calls hold the tokens of their arguments.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Range<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<Token>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenKind {
+    Dice,
+    Count,
+    DieType,
+    /// A dice option such as `w[1,2]`, `r[1]`, `-max`, `!c10`, `kh1` or `+per1`.
+    DiceOption,
+    Symbols,
+    Function,
+    Constant,
+}
+
+impl Token {
+    fn new(kind: TokenKind, span: Range<usize>) -> Self {
+        Token {
+            kind,
+            span,
+            children: Vec::new(),
+        }
+    }
+
+    fn map_span(mut self, f: &impl Fn(usize) -> usize) -> Self {
+        self.span = f(self.span.start)..f(self.span.end);
+        self.children = self.children.into_iter().map(|t| t.map_span(f)).collect();
+        self
+    }
+}
+
+/// Parses an expression as `parse` does, also returning where each part of it lies in
+/// `input`.
+pub fn parse_ast(input: &str) -> Result<Ast> {
+    let roll = parse(input)?;
+    // Normalizing leaves one ASCII byte per character, so map each back to its own offset.
+    let origin: Vec<usize> = input
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain(std::iter::once(input.len()))
+        .collect();
+    let tokens = tokenize(&normalize(input)?)
+        .into_iter()
+        .map(|t| t.map_span(&|i| origin[i]))
+        .collect();
+    Ok(Ast { roll, tokens })
+}
+
+/// Finds the tokens in normalized input that has already parsed, with spans into `input`.
+fn tokenize(input: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    // Later passes search a copy with each token blanked out, so its spans stay put.
+    let mut rest = input.to_string();
+    let blank = |rest: &mut String, span: &Range<usize>| {
+        rest.replace_range(span.clone(), &" ".repeat(span.len()));
+    };
+
+    while let Some(c) = FUNCTIONS.captures(&rest) {
+        let whole = c.whole();
+        let close = closing_paren(&rest, whole.end()).expect("Parens are already balanced");
+        let mut call = Token::new(TokenKind::Function, whole.start()..close + 1);
+        for (at, arg) in split_args(&input[whole.end()..close]) {
+            let at = whole.end() + at;
+            call.children
+                .extend(tokenize(arg).into_iter().map(|t| t.map_span(&|i| i + at)));
+        }
+        blank(&mut rest, &call.span);
+        tokens.push(call);
+    }
+
+    let symbols: Vec<Range<usize>> = SYMBOLS
+        .captures_iter(&rest)
+        .map(|c| c.whole().range())
+        .collect();
+    for span in symbols {
+        blank(&mut rest, &span);
+        tokens.push(Token::new(TokenKind::Symbols, span));
+    }
+
+    for c in DICE.captures_iter(&rest) {
+        let whole = c.whole();
+        let end = whole.start() + whole.as_str().trim_end_matches('+').trim_end().len();
+        let mut dice = Token::new(TokenKind::Dice, whole.start()..end);
+        let count = c.name("count").unwrap();
+        if !count.as_str().is_empty() {
+            dice.children
+                .push(Token::new(TokenKind::Count, count.range()));
+        }
+        dice.children.push(Token::new(
+            TokenKind::DieType,
+            c.name("dtype").unwrap().range(),
+        ));
+
+        // Options in brackets are captured without their marker and brackets.
+        let bracketed = |name| c.name(name).map(|m| m.start() - 2..m.end() + 1);
+        let explode = c.name("explode").map(|e| {
+            let end = c.name("cap").map_or(e.end(), |cap| cap.end());
+            e.start()..end
+        });
+        let trailing = |name| {
+            c.name(name)
+                .map(|m| m.start()..m.start() + m.as_str().trim_end().len())
+        };
+        let options = vec![
+            bracketed("weights"),
+            bracketed("reroll"),
+            c.name("cursed").map(|m| m.range()),
+            explode,
+            trailing("per_first"),
+            c.name("keep").map(|m| m.range()),
+            c.name("per").map(|m| m.range()),
+        ];
+        dice.children.extend(
+            options
+                .into_iter()
+                .flatten()
+                .map(|span| Token::new(TokenKind::DiceOption, span)),
+        );
+        tokens.push(dice);
+    }
+
+    let mut at = 0;
+    while let Some(c) = CONSTANTS.captures(&rest[at..]) {
+        let sign = c.name("sign").unwrap();
+        let end = c.name("const").unwrap().end();
+        tokens.push(Token::new(TokenKind::Constant, at + sign.start()..at + end));
+        at += end;
+    }
+
+    tokens.sort_by_key(|t| t.span.start);
+    tokens
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let input = "3d4+2d8+6";
+        let res = parse(input).unwrap();
+        assert_eq!(
+            res,
+            Roll {
+                dice: vec![
+                    DiceGroup::new(3, Die::new(4)),
+                    DiceGroup::new(2, Die::new(8)),
+                ],
+                constants: vec![6],
+                ..Roll::new()
+            }
+        )
+    }
+
+    #[test]
+    fn test_countless_die() {
+        assert_eq!(parse("1d6+1d4").unwrap(), parse("d6+d4").unwrap());
+        assert_eq!(
+            parse("1d20kh1-per2").unwrap(),
+            parse("d20kh1-per2").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_die_sizes() {
+        let roll = parse("1d{6,8,10}+2").unwrap();
+        assert_eq!(parse("1d6+1d8+1d10+2").unwrap(), roll);
+        assert_eq!("1d6+1d8+1d10+2", roll.to_string());
+        assert_eq!(parse("2d6!+2d8!").unwrap(), parse("2d{6, 8}!").unwrap());
+        assert_eq!(parse("1d4+1d4").unwrap(), parse("d{4,4}").unwrap());
+
+        let (_, mut rng) = seeded_rng(Some(2));
+        let res = roll.cast(1, &mut rng).unwrap();
+        let sides: Vec<u32> = res.groups.iter().map(|g| g.die.sides).collect();
+        assert_eq!(vec![6, 8, 10], sides);
+        assert!(res.groups.iter().all(|g| g.rolls.len() == 1));
+
+        // Square brackets are only weights or rerolls after a die, never sizes.
+        for input in &["1d[6-10]", "1d[0,0,1]", "1d[6,8,10]"] {
+            let err = parse(input).unwrap_err();
+            assert!(
+                matches!(err.downcast_ref::<Error>(), Some(Error::MalformedDie(_))),
+                "{}",
+                input
+            );
+        }
+        assert!(parse("1d6w[0,0,0,0,0,1]").is_ok());
+        assert!(matches!(
+            parse("1d{}").unwrap_err().downcast_ref::<Error>(),
+            Some(Error::NoSides)
+        ));
+        assert!(parse("1d{6,x}").is_err());
+    }
+
+    #[test]
+    fn test_to_canonical() {
+        let canonical = |input| parse(input).unwrap().to_canonical();
+        assert_eq!(canonical("2d6+5"), canonical("d6+1d6+2+3"));
+        assert_eq!("2d6+5", canonical("d6+1d6+2+3"));
+        assert_eq!("1d8+3d6-1", canonical("1d6+2+ 1d8+2d6-3"));
+        assert_eq!("2d20", canonical("1d20+1d20+0"));
+        assert_eq!(
+            "1d6!+2d6!c10+max(1d4,2d6)+4",
+            canonical("+4+max(1d6+1d6,1d4)+1d6!c10+1d6!+1d6!c10")
+        );
+        assert_eq!("3dF+1", canonical("1dF+1+2dF"));
+        assert_eq!("+0", canonical("+2-2"));
+
+        // Dice that are kept, unique or explode only on the highest roll can't be combined.
+        for input in &["1d20kh1+1d20kh1", "1u6+1u6", "1d6!h+1d6!h"] {
+            assert_eq!(2, parse(input).unwrap().canonical().dice.len(), "{}", input);
+        }
+        assert_ne!(canonical("4d6"), canonical("4d6+per1"));
+        assert_ne!(canonical("2d6"), canonical("2d6r[1]"));
+        assert_ne!(canonical("1d6+1d4"), canonical("1d6-1d4"));
+
+        let roll = parse("4d6+max(1d8,1d4)").unwrap();
+        let floored = roll.with_floor(2);
+        assert_ne!(roll.to_canonical(), floored.to_canonical());
+        assert_eq!("4d6floor2+max(1d4floor2,1d8floor2)", floored.to_canonical());
+        assert_eq!("4d6+max(1d8,1d4)", floored.to_string());
+        assert_ne!(
+            floored.to_canonical(),
+            roll.with_clamp(RangeInclusive::new(2, 6)).to_canonical()
+        );
+        let mut mixed = parse("1d6+1d6").unwrap();
+        mixed.dice[0].floor = Some(3);
+        assert_eq!("1d6+1d6floor3", mixed.to_canonical());
+        mixed.dice.reverse();
+        assert_eq!("1d6+1d6floor3", mixed.to_canonical());
+    }
+
+    #[test]
+    fn test_parse_ast() {
+        let ast = parse_ast("3d6+2").unwrap();
+        assert_eq!(parse("3d6+2").unwrap(), ast.roll);
+        assert_eq!(
+            vec![
+                Token {
+                    kind: TokenKind::Dice,
+                    span: 0..3,
+                    children: vec![
+                        Token::new(TokenKind::Count, 0..1),
+                        Token::new(TokenKind::DieType, 1..3),
+                    ],
+                },
+                Token::new(TokenKind::Constant, 3..5),
+            ],
+            ast.tokens
+        );
+
+        let input = "4d6r[1]!c20kh3+max(1d20, 2dF)\u{2212}3+2dGG[a,b]";
+        let ast = parse_ast(input).unwrap();
+        let text = |t: &Token| &input[t.span.clone()];
+        let kinds: Vec<TokenKind> = ast.tokens.iter().map(|t| t.kind).collect();
+        assert_eq!(
+            vec![
+                TokenKind::Dice,
+                TokenKind::Function,
+                TokenKind::Constant,
+                TokenKind::Symbols
+            ],
+            kinds
+        );
+        let parts: Vec<&str> = ast.tokens[0].children.iter().map(text).collect();
+        assert_eq!(vec!["4", "d6", "r[1]", "!c20", "kh3"], parts);
+        assert_eq!("max(1d20, 2dF)", text(&ast.tokens[1]));
+        let args: Vec<&str> = ast.tokens[1].children.iter().map(text).collect();
+        assert_eq!(vec!["1d20", "2dF"], args);
+        assert_eq!("\u{2212}3", text(&ast.tokens[2]));
+        assert_eq!("2dGG[a,b]", text(&ast.tokens[3]));
+
+        assert!(parse_ast("3d").is_err());
+    }
+
+    #[test]
+    fn test_from_str() {
+        let roll: Roll = "2d6+3".parse().unwrap();
+        assert_eq!(parse("2d6+3").unwrap(), roll);
+        assert_eq!(
+            Roll::builder()
+                .add_dice(2, Die::new(6))
+                .add_modifier(3)
+                .build(),
+            roll
+        );
+
+        let err = "3d".parse::<Roll>().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::MalformedDie(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_keep() {
+        let res = parse("4d6dl1+2d20kh").unwrap();
+        assert_eq!(Some(Keep::DropLowest(1)), res.dice[0].keep);
+        assert_eq!(Some(Keep::Highest(1)), res.dice[1].keep);
+
+        let err = parse("4d6kh99999999999").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidCount { token, .. }) if token == "kh99999999999"
+        ));
+        assert_eq!(
+            "Invalid count in \"dl99999999999\": number too large to fit in target type",
+            parse("4d6dl99999999999").unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_subtract_lowest() {
+        let roll = parse("3d6-l1+2").unwrap();
+        assert_eq!(Some(Keep::SubtractLowest(1)), roll.dice[0].keep);
+        assert_eq!(vec![2], roll.constants);
+        assert_eq!("3d6-l1+2", roll.to_string());
+        let err = parse("3d6-l99999999999").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidCount { token, .. }) if token == "-l99999999999"
+        ));
+
+        let (_, mut rng) = seeded_rng(Some(12));
+        for res in roll.cast_n(50, 1, &mut rng).unwrap() {
+            let values: Vec<i32> = res.groups[0].rolls.iter().map(|d| d.value).collect();
+            let lowest = values.iter().min().unwrap();
+            assert_eq!(values.iter().sum::<i32>() - lowest + 2, res.total);
+        }
+
+        assert!(parse("1d6-l1").unwrap().validate().is_err());
+        assert_eq!(6, parse("2d6-l1").unwrap().max_total(1));
+    }
+
+    #[test]
+    fn test_split_exprs() {
+        assert_eq!(
+            vec!["1d20+5", "2d6+3", "1d4"],
+            split_exprs("1d20+5, 2d6+3,1d4")
+        );
+        assert_eq!(
+            vec!["max(1d20,1d12)", "1d{6,8}w[1,2,3,4,5,6]"],
+            split_exprs(" max(1d20,1d12) ,, 1d{6,8}w[1,2,3,4,5,6], ")
+        );
+        assert!(split_exprs(" , ").is_empty());
+
+        let roll = parse("max(1d{6,8},1d4)").unwrap();
+        assert_eq!(2, roll.functions[0].args().len());
+    }
+
+    #[test]
+    fn test_group_sorted() {
+        let (_, mut rng) = seeded_rng(Some(14));
+        let res = parse("3d6+2d8").unwrap().cast(1, &mut rng).unwrap();
+        let sorted = res.group_sorted();
+        assert_eq!(res.total, sorted.total);
+        let sides: Vec<u32> = sorted.groups.iter().map(|g| g.die.sides).collect();
+        assert_eq!(vec![8, 6], sides);
+        for (group, count) in sorted.groups.iter().zip(&[2, 3]) {
+            assert_eq!(*count, group.rolls.len());
+            assert!(group.rolls.windows(2).all(|w| w[0].value >= w[1].value));
+        }
+        let mut values = res.values();
+        let mut sorted_values = sorted.values();
+        values.sort();
+        sorted_values.sort();
+        assert_eq!(values, sorted_values);
+
+        let res = parse("1d6+1d8+2d6").unwrap().cast(1, &mut rng).unwrap();
+        let sorted = res.group_sorted();
+        assert_eq!(2, sorted.groups.len());
+        assert_eq!(3, sorted.groups[1].rolls.len());
+    }
+
+    #[test]
+    fn test_malformed_die() {
+        for input in &["3d", "3d+2", "1d6+3d"] {
+            let err = parse(input).unwrap_err();
+            match err.downcast_ref::<Error>() {
+                Some(Error::MalformedDie(token)) => assert_eq!("3d", token),
+                _ => panic!("Expected MalformedDie for {:?}, got {:?}", input, err),
+            }
+        }
+        assert!(parse("4d6dl1+2d8kh").is_ok());
+    }
+
+    #[test]
+    fn test_keep_apply() {
+        let scores = [3, 6, 1, 4];
+        assert_eq!(
+            vec![false, true, false, true],
+            Keep::Highest(2).apply(&scores)
+        );
+        assert_eq!(
+            vec![false, false, true, false],
+            Keep::Lowest(1).apply(&scores)
+        );
+        assert_eq!(
+            vec![true, false, true, true],
+            Keep::DropHighest(1).apply(&scores)
+        );
+        assert_eq!(
+            vec![true, true, false, true],
+            Keep::DropLowest(1).apply(&scores)
+        );
+        assert_eq!(vec![true; 4], Keep::Highest(9).apply(&scores));
+
+        let penalised = [0, 2, -1, 1, 1];
+        assert_eq!(
+            vec![false, false, false, true, false],
+            Keep::LowestNonzero.apply(&penalised)
+        );
+        assert_eq!(vec![false; 3], Keep::LowestNonzero.apply(&[0, -2, 0]));
+    }
+
+    #[test]
+    fn test_merge() {
+        let attack = parse("1d20+5").unwrap();
+        let buff = parse("2d6+1d4+2").unwrap();
+        let merged = attack.clone().merge(buff.clone());
+        assert_eq!(parse("1d20+5+2d6+1d4+2").unwrap(), merged);
+        assert_eq!(vec![5, 2], merged.constants);
+
+        for seed in 0..10 {
+            let (_, mut rng) = seeded_rng(Some(seed));
+            let total = merged.cast(1, &mut rng).unwrap().total;
+            let (_, mut rng) = seeded_rng(Some(seed));
+            let parts =
+                attack.cast(1, &mut rng).unwrap().total + buff.cast(1, &mut rng).unwrap().total;
+            assert_eq!(parts, total);
+        }
+
+        let with_max = parse("1d8")
+            .unwrap()
+            .merge(parse("max(1d4,1d6)-1").unwrap());
+        assert_eq!(1, with_max.functions.len());
+        assert_eq!(Roll::new(), Roll::new().merge(Roll::new()));
+    }
+
+    #[test]
+    fn test_builder_matches_parse() {
+        let built = Roll::builder()
+            .add_dice(4, Die::new(6))
+            .drop_lowest(1)
+            .add_dice(2, Die::new(8))
+            .explode(Explode::Each)
+            .add_modifier(3)
+            .build();
+        assert_eq!(parse("4d6dl1+2d8!+3").unwrap(), built);
+    }
+
+    #[test]
+    fn test_from_parts() {
+        let roll = Roll::from_parts(vec![(2, Die::new(6)), (1, Die::new(4))], vec![3]).unwrap();
+        assert_eq!(parse("2d6+1d4+3").unwrap(), roll);
+
+        let (_, mut rng) = seeded_rng(Some(21));
+        let res = roll.cast(1, &mut rng).unwrap();
+        let dice: i32 = res.groups.iter().map(GroupResult::subtotal).sum();
+        assert_eq!(3, res.groups[0].rolls.len() + res.groups[1].rolls.len());
+        assert_eq!(dice + 3, res.total);
+
+        assert!(Roll::from_parts(vec![(1, Die::new(0))], vec![]).is_err());
+        assert!(Roll::from_parts(vec![], vec![]).is_err());
+    }
+
+    #[test]
+    fn test_cast_n() {
+        let roll = parse("4d6dl1+2").unwrap();
+        let (_, mut rng) = seeded_rng(Some(17));
+        let results = roll.cast_n(1000, 1, &mut rng).unwrap();
+        assert_eq!(1000, results.len());
+        assert!(results.iter().all(|r| (5..=20).contains(&r.total)));
+
+        let (_, mut rng) = seeded_rng(Some(17));
+        let first = roll.cast(1, &mut rng).unwrap();
+        assert_eq!(first, results[0]);
+
+        assert!(Roll::new().cast_n(3, 1, &mut rng).is_err());
+    }
+
+    #[test]
+    fn test_seed_reproduces_total() {
+        let roll = parse("10d20+3").unwrap();
+        let (seed, mut rng) = seeded_rng(None);
+        let first = roll.cast(1, &mut rng).unwrap();
+
+        let (replayed, mut rng) = seeded_rng(Some(seed));
+        assert_eq!(seed, replayed);
+        assert_eq!(first.total, roll.cast(1, &mut rng).unwrap().total);
+    }
+
+    #[test]
+    fn test_session_rng() {
+        let roll = parse("3d6+1").unwrap();
+        for kind in &["thread", "secure", "seeded"] {
+            let (seed, mut rng) = session_rng(kind.parse().unwrap(), None);
+            assert_eq!(*kind == "seeded", seed.is_some());
+            for res in roll.cast_n(200, 1, &mut rng).unwrap() {
+                assert!((4..=19).contains(&res.total), "{}: {}", kind, res.total);
+            }
+        }
+
+        let (_, mut rng) = session_rng(RngKind::Seeded, Some(17));
+        let (_, mut again) = seeded_rng(Some(17));
+        assert_eq!(
+            roll.cast(1, &mut again).unwrap(),
+            roll.cast(1, &mut rng).unwrap()
+        );
+
+        let err = "fast".parse::<RngKind>().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::UnknownRng(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_functions() {
+        let res = parse("max(1d20,1d12)+min(2d4,max(1d6,1d8))+3").unwrap();
+        assert_eq!(vec![3], res.constants);
+        assert!(res.dice.is_empty());
+        assert_eq!(
+            Function::Max(vec![parse("1d20").unwrap(), parse("1d12").unwrap()]),
+            res.functions[0]
+        );
+        assert_eq!(
+            Function::Min(vec![parse("2d4").unwrap(), parse("max(1d6,1d8)").unwrap()]),
+            res.functions[1]
+        );
+
+        let weighted = parse("max(1d4w[1,2,3,4],1d4)").unwrap();
+        assert_eq!(2, weighted.functions[0].args().len());
+    }
+
+    #[test]
+    fn test_unique() {
+        let roll = parse("6u6").unwrap();
+        assert!(roll.dice[0].unique);
+
+        let (_, mut rng) = seeded_rng(Some(3));
+        let res = roll.cast(1, &mut rng).unwrap();
+        let mut values: Vec<i32> = res.groups[0].rolls.iter().map(|r| r.value).collect();
+        values.sort();
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], values);
+    }
+
+    #[test]
+    fn test_unique_impossible() {
+        let (_, mut rng) = seeded_rng(Some(3));
+        let err = parse("7u6").unwrap().cast(1, &mut rng).unwrap_err();
+        assert_eq!("Cannot roll 7 unique results on a d6", err.to_string());
+    }
+
+    #[test]
+    fn test_weighted() {
+        let roll = parse("1d6w[1,1,1,1,1,3]").unwrap();
+        assert_eq!(
+            Some(vec![1.0, 1.0, 1.0, 1.0, 1.0, 3.0]),
+            roll.dice[0].die.weights
+        );
+
+        let (_, mut rng) = seeded_rng(Some(11));
+        let mut counts = [0; 6];
+        for _ in 0..8000 {
+            let value = roll.dice[0].die.roll(&mut rng);
+            counts[value as usize - 1] += 1;
+        }
+        for &c in &counts[..5] {
+            assert!(counts[5] > c * 2, "{:?}", counts);
+        }
+
+        let loaded = parse("3d4w[0,0,0,1]").unwrap();
+        assert_eq!(12, loaded.cast(1, &mut rng).unwrap().total);
+        assert_eq!(12, loaded.average(1));
+    }
+
+    #[test]
+    fn test_weighted_invalid() {
+        assert!(parse("1d6w[1,1,1]").is_err());
+        assert!(parse("1d4w[1,-1,1,1]").is_err());
+        assert!(parse("1d2w[0,0]").is_err());
+        assert!(parse("1d2w[a,1]").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(parse("4d6dl1+1d20").unwrap().validate().is_ok());
+        assert!(parse("+3").unwrap().validate().is_ok());
+
+        let invalid = vec![
+            (Roll::new(), "Nothing to roll"),
+            (
+                Roll::builder()
+                    .add_dice(3, Die::new(6))
+                    .keep_highest(5)
+                    .build(),
+                "Cannot kh5 from 3 dice",
+            ),
+            (
+                Roll::builder()
+                    .add_dice(2, Die::new(6))
+                    .drop_lowest(2)
+                    .build(),
+                "Cannot dl2 from 2 dice",
+            ),
+            (
+                Roll::builder().add_dice(1, Die::new(0)).build(),
+                "Dice must have at least one side",
+            ),
+            (
+                Roll {
+                    functions: vec![Function::Max(vec![])],
+                    ..Roll::new()
+                },
+                "max() needs at least one argument",
+            ),
+            (
+                Roll {
+                    functions: vec![Function::Min(vec![Roll::new()])],
+                    ..Roll::new()
+                },
+                "Nothing to roll",
+            ),
+        ];
+
+        let (_, mut rng) = seeded_rng(Some(1));
+        for (roll, message) in invalid {
+            assert_eq!(message, roll.validate().unwrap_err().to_string());
+            assert!(roll.cast(1, &mut rng).is_err());
+        }
+    }
+
+    #[test]
+    fn test_huge_dice() {
+        let (_, mut rng) = seeded_rng(Some(1));
+        for input in &["1d3000000000", "1d4294967295", "1d2147483647"] {
+            let err = parse(input).unwrap().cast(1, &mut rng).unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref::<Error>(),
+                    Some(Error::TooManySides { max: MAX_SIDES, .. })
+                ),
+                "{}",
+                input
+            );
+        }
+        assert!(roll_die(4_294_967_295, &mut rng).is_err());
+        assert!(Roll::builder()
+            .add_dice(1, Die::new(u32::MAX))
+            .build()
+            .validate()
+            .is_err());
+
+        for input in &[
+            "10000d2147483646",
+            "1d2147483646+1d2147483646",
+            "1d2000000000+1000000000",
+        ] {
+            let err = parse(input).unwrap().cast(1, &mut rng).unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref::<Error>(),
+                    Some(Error::TotalTooLarge { .. })
+                ),
+                "{}",
+                input
+            );
+        }
+        assert_eq!(
+            "1000d100000! could total more than 2147483647",
+            parse("1000d100000!")
+                .unwrap()
+                .validate()
+                .unwrap_err()
+                .to_string()
+        );
+        assert!(parse("1d1000000000").unwrap().cast(2, &mut rng).is_ok());
+        assert!(parse("1d2000000000").unwrap().cast(2, &mut rng).is_err());
+
+        let res = parse("1d2147483646").unwrap().cast(1, &mut rng).unwrap();
+        assert!(res.total >= 1);
+    }
+
+    #[test]
+    fn test_explode() {
+        let roll = parse("20d2!").unwrap();
+        assert_eq!(Some(Explode::Each), roll.dice[0].explode);
+
+        let (_, mut rng) = seeded_rng(Some(8));
+        let res = roll.cast(1, &mut rng).unwrap();
+        for die in &res.groups[0].rolls {
+            assert_eq!(2 * die.explosions as i32 + 1, die.value);
+        }
+        assert!(res.explosions() > 0);
+        assert_eq!(
+            res.longest_chain(),
+            res.groups[0]
+                .rolls
+                .iter()
+                .map(|d| d.explosions)
+                .max()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_explode_then_keep() {
+        let roll = parse("4d6!kh3").unwrap();
+        let die = Die::new(6);
+        let mut kept_explosion = false;
+        for seed in 0..50 {
+            let (_, mut rng) = seeded_rng(Some(seed));
+            let res = roll.cast(1, &mut rng).unwrap();
+
+            let (_, mut rng) = seeded_rng(Some(seed));
+            let mut expected: Vec<i32> = (0..4).map(|_| die.roll(&mut rng)).collect();
+            for value in expected.iter_mut() {
+                let mut last = *value;
+                while last == 6 {
+                    last = die.roll(&mut rng);
+                    *value += last;
+                }
+            }
+            let all: Vec<i32> = res.groups[0].rolls.iter().map(|d| d.value).collect();
+            assert_eq!(expected, all);
+
+            expected.sort_unstable();
+            let mut kept = res.values();
+            kept.sort_unstable();
+            assert_eq!(expected[1..], kept[..]);
+            assert_eq!(expected[1..].iter().sum::<i32>(), res.total);
+            kept_explosion |= kept.iter().any(|v| *v > 6);
+        }
+        assert!(kept_explosion);
+    }
+
+    #[test]
+    fn test_explode_highest() {
+        let roll = parse("3d6!h").unwrap();
+        assert_eq!(Some(Explode::Highest), roll.dice[0].explode);
+
+        let (_, mut rng) = seeded_rng(Some(2));
+        let mut exploded = 0;
+        for _ in 0..200 {
+            let res = roll.cast(1, &mut rng).unwrap();
+            let rolls = &res.groups[0].rolls;
+            assert!(rolls.iter().filter(|d| d.explosions > 0).count() <= 1);
+            if let Some(top) = rolls.iter().position(|d| d.explosions > 0) {
+                assert!(rolls[top].value > 6);
+                assert!(rolls[..top].iter().all(|d| d.value < 6));
+                exploded += 1;
+            }
+        }
+        assert!(exploded > 0);
+
+        let (_, mut rng) = seeded_rng(Some(2));
+        let res = parse("3d1!h").unwrap().cast(1, &mut rng).unwrap();
+        assert_eq!(MAX_EXPLOSIONS, res.groups[0].rolls[0].explosions);
+        assert_eq!(0, res.groups[0].rolls[1].explosions);
+        assert_eq!(0, res.groups[0].rolls[2].explosions);
+    }
+
+    #[test]
+    fn test_explode_forced() {
+        let (_, mut rng) = seeded_rng(Some(8));
+        let res = parse("3d1!").unwrap().cast(1, &mut rng).unwrap();
+        assert_eq!(3 * MAX_EXPLOSIONS, res.explosions());
+        assert_eq!(MAX_EXPLOSIONS, res.longest_chain());
+        assert_eq!(3 * (MAX_EXPLOSIONS as i32 + 1), res.total);
+    }
+
+    #[test]
+    fn test_best_effort() {
+        let roll = parse("20000d6!kh3+max(1d4,12000d8)+2").unwrap();
+        let err = roll.validate().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::TooManyDice {
+                count: 20000,
+                max: MAX_DICE
+            })
+        ));
+
+        let (clamped, warnings) = roll.best_effort();
+        assert_eq!(
+            vec![
+                "Rolling 10000d6kh3 instead of 20000d6!kh3: at most 10000 dice can be rolled at once",
+                "Rolling 10000d8 instead of 12000d8: at most 10000 dice can be rolled at once",
+            ],
+            warnings
+        );
+        let (_, mut rng) = seeded_rng(Some(3));
+        let res = clamped.cast(1, &mut rng).unwrap();
+        assert_eq!(0, res.explosions());
+        assert!(res.total >= 3 + 10000 + 2);
+
+        let (same, warnings) = parse("4d6!").unwrap().best_effort();
+        assert_eq!("4d6!", same.to_string());
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_roll_verbose() {
+        let (_, mut rng) = seeded_rng(Some(6));
+        let res = parse("2d6!+1d4+3").unwrap().cast(1, &mut rng).unwrap();
+        let (_, mut rng) = seeded_rng(Some(6));
+        let out = roll_verbose("2d6!+1d4+3", &mut rng).unwrap();
+
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(5, lines.len());
+        assert_eq!(res.groups[0].rolls[0].to_string(), lines[0]);
+        assert_eq!(&["---", res.total.to_string().as_str()], &lines[3..]);
+        assert!(!out.contains('\x1b'));
+
+        let out = roll_verbose("1dHIT[hit,miss,miss]", &mut rng).unwrap();
+        assert!(out.starts_with("HIT: "));
+        assert!(out.contains("Symbols: "));
+        assert!(roll_verbose("1d0", &mut rng).is_err());
+    }
+
+    #[test]
+    fn test_reroll() {
+        let roll = parse("2d6r[1,5]+1").unwrap();
+        assert_eq!(vec![1, 5], roll.dice[0].reroll);
+        assert_eq!(vec![1], roll.constants);
+        assert_eq!(
+            Roll::builder()
+                .add_dice(2, Die::new(6))
+                .reroll(vec![1, 5])
+                .add_modifier(1)
+                .build(),
+            roll
+        );
+
+        // Each die rerolls at most once, in order, and keeps the second roll.
+        let die = Die::new(6);
+        for seed in 0..20 {
+            let (_, mut rng) = seeded_rng(Some(seed));
+            let res = roll.cast(1, &mut rng).unwrap();
+            let (_, mut rng) = seeded_rng(Some(seed));
+            let expected: Vec<i32> = (0..2)
+                .map(|_| match die.roll(&mut rng) {
+                    1 | 5 => die.roll(&mut rng),
+                    n => n,
+                })
+                .collect();
+            assert_eq!(expected, res.values());
+        }
+
+        // A rerolled die can land on a reroll face again; it is not rerolled a second time.
+        let (_, mut rng) = seeded_rng(Some(3));
+        let res = parse("5d1r[1]").unwrap().cast(1, &mut rng).unwrap();
+        assert_eq!(vec![1; 5], res.values());
+
+        let chances = parse("1d4r[1]").unwrap().dice[0].chances();
+        assert_eq!(
+            vec![1.0 / 16.0, 5.0 / 16.0, 5.0 / 16.0, 5.0 / 16.0],
+            chances
+        );
+        assert_eq!(2, parse("1d4r[1]").unwrap().average(1));
+        assert!((parse("1d4r[1]").unwrap().mean(1) - 2.875).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_max_min_count() {
+        let (_, mut rng) = seeded_rng(Some(6));
+        let res = parse("4d1+2d20").unwrap().cast(1, &mut rng).unwrap();
+        let twenties = res.groups[1].rolls.iter().filter(|r| r.value == 20).count();
+        let ones = res.groups[1].rolls.iter().filter(|r| r.value == 1).count();
+        assert_eq!(4 + twenties, res.max_count());
+        assert_eq!(4 + ones, res.min_count());
+
+        // Explosions and bonuses change the value but not the natural roll.
+        let res = parse("3d1!c3+per2").unwrap().cast(1, &mut rng).unwrap();
+        assert_eq!(vec![5, 5, 5], res.values());
+        assert_eq!((3, 3), (res.max_count(), res.min_count()));
+
+        let res = parse("6dF").unwrap().cast(1, &mut rng).unwrap();
+        let count = |v| res.values().iter().filter(|n| **n == v).count();
+        assert_eq!((count(1), count(-1)), (res.max_count(), res.min_count()));
+
+        let res = parse("5d6kh1").unwrap().cast(1, &mut rng).unwrap();
+        let naturals: Vec<i32> = res.groups[0].rolls.iter().map(|r| r.natural).collect();
+        assert_eq!(5, naturals.len());
+        assert_eq!(
+            naturals.iter().filter(|n| **n == 6).count(),
+            res.max_count()
+        );
+    }
+
+    #[test]
+    fn test_explode_cap() {
+        let roll = parse("3d6!c18").unwrap();
+        assert_eq!(Some(18), roll.dice[0].explode_cap);
+        assert_eq!(
+            Roll::builder()
+                .add_dice(3, Die::new(6))
+                .explode(Explode::Each)
+                .explode_cap(18)
+                .build(),
+            roll
+        );
+
+        let (_, mut rng) = seeded_rng(Some(2));
+        let res = parse("3d1!c5").unwrap().cast(1, &mut rng).unwrap();
+        assert_eq!(vec![5, 5, 5], res.values());
+        assert_eq!(12, res.explosions());
+
+        let roll = parse("100d6!c8").unwrap();
+        let mut exploded = 0;
+        for seed in 0..10 {
+            let (_, mut rng) = seeded_rng(Some(seed));
+            let res = roll.cast(1, &mut rng).unwrap();
+            assert!(res.values().iter().all(|v| *v <= 8));
+            exploded += res.explosions();
+        }
+        assert!(exploded > 0);
+
+        // A 6 explodes once more, to 7 or else the cap of 8.
+        assert!((parse("1d6!c8").unwrap().mean(1) - (2.5 + (7.0 + 5.0 * 8.0) / 36.0)).abs() < 1e-9);
+        assert!((parse("1d6!c6").unwrap().mean(1) - 3.5).abs() < 1e-9);
+        assert!(parse("1d6!c5").unwrap().validate().is_err());
+    }
+
+    #[test]
+    fn test_per_die_before_keep() {
+        let roll = parse("3d6+per1 kh2").unwrap();
+        assert_eq!(roll, parse("3d6kh2+per1").unwrap());
+        assert_eq!(roll, parse("3d6+per1kh2").unwrap());
+        assert_eq!(Some(Keep::Highest(2)), roll.dice[0].keep);
+        assert_eq!(1, roll.dice[0].per_die);
+
+        let roll = parse("3d6+per1 kh2+2").unwrap();
+        assert_eq!(vec![2], roll.constants);
+        for seed in 0..20 {
+            let (_, mut rng) = seeded_rng(Some(seed));
+            let res = roll.cast(2, &mut rng).unwrap();
+            let mut all: Vec<i32> = res.groups[0].rolls.iter().map(|d| d.value).collect();
+            assert!(all.iter().all(|v| (2..=7).contains(v)));
+            all.sort_unstable();
+            assert_eq!((all[1] + all[2]) * 2 + 2, res.total);
+        }
+    }
+
+    #[test]
+    fn test_cursed() {
+        let roll = parse("3d6-max+2").unwrap();
+        assert!(roll.dice[0].cursed);
+        assert_eq!(vec![2], roll.constants);
+
+        let (_, mut rng) = seeded_rng(Some(4));
+        let mut seen = Vec::new();
+        for res in parse("10d6-max").unwrap().cast_n(100, 1, &mut rng).unwrap() {
+            seen.extend(res.values());
+        }
+        assert!(seen.iter().all(|v| (1..=5).contains(v)));
+        assert!(seen.contains(&5));
+
+        let res = parse("20d2-max!").unwrap().cast(1, &mut rng).unwrap();
+        assert_eq!(vec![1; 20], res.values());
+        assert_eq!(0, res.explosions());
+        assert!((parse("1d6-max!").unwrap().mean(1) - 3.0).abs() < 1e-9);
+
+        for input in &["1d1-max", "6u6-max"] {
+            assert!(parse(input).unwrap().validate().is_err(), "{}", input);
+        }
+        assert!(parse("5u6-max").unwrap().validate().is_ok());
+    }
+
+    #[test]
+    fn test_fudge() {
+        let roll = parse("4dF+1").unwrap();
+        assert_eq!(DiceGroup::fudge(4), roll.dice[0]);
+        assert_eq!(vec![1], roll.constants);
+        assert!((roll.mean(1) - 1.0).abs() < 1e-9);
+
+        let (_, mut rng) = seeded_rng(Some(10));
+        let mut seen = Vec::new();
+        for res in roll.cast_n(50, 1, &mut rng).unwrap() {
+            let values = res.values();
+            assert_eq!(values.iter().sum::<i32>() + 1, res.total);
+            seen.extend(values);
+        }
+        seen.sort_unstable();
+        seen.dedup();
+        assert_eq!(vec![-1, 0, 1], seen);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let roll = parse("1d6+3dFFG[blank,success,success,advantage]+2").unwrap();
+        assert_eq!(
+            vec![SymbolDice {
+                count: 3,
+                name: "FFG".to_string(),
+                faces: vec!["blank", "success", "success", "advantage"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+            }],
+            roll.symbols
+        );
+        assert_eq!(vec![2], roll.constants);
+        assert_eq!(1, roll.dice.len());
+
+        let (_, mut rng) = seeded_rng(Some(14));
+        let mut successes = 0;
+        for res in roll.cast_n(200, 1, &mut rng).unwrap() {
+            assert_eq!(res.groups[0].subtotal() + 2, res.total);
+            let tally = res.tally();
+            assert_eq!(3, tally.values().sum::<usize>());
+            assert!(tally
+                .keys()
+                .all(|k| ["blank", "success", "advantage"].contains(k)));
+            successes += tally.get("success").cloned().unwrap_or(0);
+        }
+        // Half the faces are successes.
+        assert!((250..350).contains(&successes), "{}", successes);
+
+        assert!(parse("1dFFG[ ]").unwrap().validate().is_err());
+    }
+
+    #[test]
+    fn test_floor() {
+        let roll = parse("3d6-per2").unwrap().with_floor(1);
+        assert_eq!(
+            Roll::builder()
+                .add_dice(3, Die::new(6))
+                .per_die(-2)
+                .floor(1)
+                .build(),
+            roll
+        );
+
+        let (_, mut rng) = seeded_rng(Some(31));
+        let mut floored = 0;
+        for res in roll.cast_n(200, 1, &mut rng).unwrap() {
+            for die in &res.groups[0].rolls {
+                assert_eq!((die.natural - 2).max(1), die.value);
+                floored += (die.natural <= 3) as u32;
+            }
+        }
+        assert!(floored > 0);
+
+        assert_eq!(3, roll.min_total(1));
+        assert_eq!(12, roll.max_total(1));
+        let dist = roll.distribution(1, 1000).unwrap();
+        assert_eq!(3, dist.min());
+        assert!((dist.mean() - roll.expected_value()).abs() < 1e-9);
+
+        let nested = parse("max(2d4-per3,1d6)").unwrap().with_floor(0);
+        assert!(nested.functions[0]
+            .args()
+            .iter()
+            .all(|a| a.dice[0].floor == Some(0)));
+    }
+
+    #[test]
+    fn test_clamp() {
+        let roll = parse("1d20+2d6+1d4-per3").unwrap().with_clamp(2..=5);
+        let (_, mut rng) = seeded_rng(Some(32));
+        let mut clamped = (false, false);
+        for res in roll.cast_n(200, 1, &mut rng).unwrap() {
+            for die in res.groups.iter().flat_map(|g| &g.rolls) {
+                assert!((2..=5).contains(&die.value));
+                clamped.0 |= die.natural < 2;
+                clamped.1 |= die.natural > 5;
+            }
+        }
+        assert_eq!((true, true), clamped);
+        assert_eq!(8, roll.min_total(1));
+        assert_eq!(17, roll.max_total(1));
+        assert_eq!(
+            Roll::builder()
+                .add_dice(1, Die::new(20))
+                .floor(2)
+                .ceiling(5)
+                .build(),
+            parse("1d20").unwrap().with_clamp(2..=5)
+        );
+
+        let floored = parse("1d6").unwrap().with_floor(4).with_clamp(2..=5);
+        assert_eq!(Some(4), floored.dice[0].floor);
+
+        let err = parse("1d6")
+            .unwrap()
+            .with_clamp(RangeInclusive::new(5, 2))
+            .validate()
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidClamp { low: 5, high: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_per_die() {
+        let roll = parse("8d8+per2+1").unwrap();
+        assert_eq!(2, roll.dice[0].per_die);
+        assert_eq!(vec![1], roll.constants);
+        assert_eq!(-2, parse("3d6-per2").unwrap().dice[0].per_die);
+        assert_eq!(
+            Roll::builder().add_dice(8, Die::new(8)).per_die(2).build(),
+            parse("8d8+per2").unwrap()
+        );
+
+        let (_, mut rng) = seeded_rng(Some(30));
+        let res = parse("8d8+per2").unwrap().cast(1, &mut rng).unwrap();
+        let values = res.values();
+        assert!(values.iter().all(|v| (3..=10).contains(v)));
+        let dice_sum: i32 = values.iter().map(|v| v - 2).sum();
+        assert_eq!(dice_sum + 16, res.total);
+    }
+
+    #[test]
+    fn test_keep_lowest_nonzero() {
+        let roll = parse("4d6klnz-per3").unwrap();
+        assert_eq!(Some(Keep::LowestNonzero), roll.dice[0].keep);
+
+        let (_, mut rng) = seeded_rng(Some(40));
+        for res in roll.cast_n(100, 1, &mut rng).unwrap() {
+            let rolls = &res.groups[0].rolls;
+            let positive: Vec<i32> = rolls.iter().map(|d| d.value).filter(|v| *v > 0).collect();
+            match positive.iter().min() {
+                Some(lowest) => assert_eq!(*lowest, res.total),
+                None => assert_eq!(0, res.total),
+            }
+            assert!(rolls.iter().filter(|d| d.kept).count() <= 1);
+        }
+
+        let res = parse("3d1klnz-per1").unwrap().cast(1, &mut rng).unwrap();
+        assert_eq!(0, res.total);
+    }
+
+    #[test]
+    fn test_expected_value() {
+        assert_eq!(10.0, parse("2d6+3").unwrap().expected_value());
+        assert_eq!(10.5, parse("1d20").unwrap().expected_value());
+        assert_eq!(17.0, parse("2d6+3").unwrap().expected_value_with_crit(2));
+        assert_eq!(0.0, parse("4dF").unwrap().expected_value());
+        assert_eq!(10.5, parse("4d6dl1").unwrap().expected_value());
+        assert!((parse("1d6!").unwrap().expected_value() - 4.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_average() {
+        assert_eq!(13, parse("2d8+4").unwrap().average(1));
+        assert_eq!(3, parse("1d6").unwrap().average(1));
+        assert_eq!(10, parse("3d6").unwrap().average(1));
+        assert_eq!(22, parse("2d8+4").unwrap().average(2));
+        assert_eq!(10, parse("max(1d20,1d12)").unwrap().average(1));
+        assert_eq!(4, parse("1d6!").unwrap().average(1));
+        assert_eq!(52, parse("8d8+per2").unwrap().average(1));
+    }
+
+    #[test]
+    fn test_roll_die() {
+        let (_, mut rng) = seeded_rng(Some(12));
+        let mut seen = [false; 6];
+        for _ in 0..1000 {
+            let n = roll_die(6, &mut rng).unwrap();
+            assert!((1..=6).contains(&n), "{}", n);
+            seen[n as usize - 1] = true;
+        }
+        assert!(seen.iter().all(|s| *s));
+
+        assert_eq!(1, roll_die(1, &mut rng).unwrap());
+        assert!(roll_die(0, &mut rng).is_err());
+    }
+
+    #[test]
+    fn test_crit_chance() {
+        assert_eq!(0.05, crit_chance(20).unwrap());
+        assert_eq!(0.15, crit_chance(18).unwrap());
+        assert_eq!(0.95, crit_chance(2).unwrap());
+        assert!(crit_chance(1).is_err());
+        assert!(crit_chance(21).is_err());
+    }
+
+    #[test]
+    fn test_avg() {
+        let roll = parse("avg(2d8)+1d6").unwrap();
+        assert_eq!(
+            Function::Avg(Box::new(parse("2d8").unwrap())),
+            roll.functions[0]
+        );
+        assert_eq!(1, roll.dice.len());
+
+        let (_, mut rng) = seeded_rng(Some(9));
+        for res in roll.cast_n(50, 1, &mut rng).unwrap() {
+            let d6 = res.groups[0].rolls[0].value;
+            assert_eq!(9, res.functions[0].value);
+            assert_eq!(9 + d6, res.total);
+        }
+
+        let res = parse("avg(2d8+4)").unwrap().cast(2, &mut rng).unwrap();
+        assert_eq!(22, res.total);
+        assert!(parse("avg(1d6,1d8)").is_err());
+    }
+
+    #[test]
+    fn test_crit_tagging() {
+        let roll = parse("crit(1d8+2)+2d6+1").unwrap();
+        assert_eq!(
+            Function::Crit(Box::new(parse("1d8+2").unwrap())),
+            roll.functions[0]
+        );
+        assert_eq!("2d6+crit(1d8+2)+1", roll.to_string());
+
+        let (_, mut rng) = seeded_rng(Some(21));
+        for res in roll.cast_n(30, 2, &mut rng).unwrap() {
+            let d8 = res.functions[0].values[0].groups[0].subtotal();
+            let d6 = res.groups[0].subtotal();
+            assert_eq!(d8 * 2 + 2, res.functions[0].value);
+            assert_eq!(d8 * 2 + 2 + d6 + 1, res.total);
+        }
+        assert!((roll.mean(2) - (9.0 + 2.0 + 7.0 + 1.0)).abs() < 1e-9);
+
+        // Untagged rolls still multiply every die.
+        let res = parse("2d1+1d1").unwrap().cast(2, &mut rng).unwrap();
+        assert_eq!(6, res.total);
+        assert!(parse("crit(1d8,1d6)").is_err());
+    }
+
+    #[test]
+    fn test_best() {
+        let roll = parse("best(3, 4d6dl1)").unwrap();
+        assert_eq!(
+            Function::Best {
+                times: 3,
+                roll: Box::new(parse("4d6dl1").unwrap())
+            },
+            roll.functions[0]
+        );
+
+        let (_, mut rng) = seeded_rng(Some(17));
+        for res in roll.cast_n(20, 1, &mut rng).unwrap() {
+            let function = &res.functions[0];
+            let totals: Vec<i32> = function.values.iter().map(|v| v.total).collect();
+            assert_eq!(3, totals.len());
+            assert_eq!(*totals.iter().max().unwrap(), function.value);
+            assert_eq!(function.value, totals[function.chosen]);
+            assert_eq!(function.value, res.total);
+        }
+
+        for input in &["best(0,1d6)", "best(x,1d6)", "best(3)", "best(2,1d6,1d8)"] {
+            assert!(
+                parse(input).and_then(|r| r.validate()).is_err(),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_best_mean() {
+        for (input, mean) in &[
+            ("best(2,1d2)", 1.75),
+            ("best(3,1d2+1)", 2.875),
+            ("best(1,1d6)", 3.5),
+        ] {
+            let found = parse(input).unwrap().functions[0].mean(1);
+            assert!((found - mean).abs() < 1e-9, "{}: {}", input, found);
+        }
+
+        let exploding = parse("best(2,1d6!)").unwrap().functions[0].mean(1);
+        assert!(exploding > parse("1d6!").unwrap().expected_value() + 0.5);
+        assert_eq!(
+            exploding,
+            parse("best(2,1d6!)").unwrap().functions[0].mean(1)
+        );
+    }
+
+    #[test]
+    fn test_parse_bad_functions() {
+        assert!(parse("max(1d20,1d12").is_err());
+        assert!(parse("min()").is_err());
+    }
+
+    #[test]
+    fn test_trailing_junk() {
+        for (input, c) in &[
+            ("1d6 foo", 'f'),
+            ("1d6)", ')'),
+            ("(1d6", '('),
+            ("max(1d6,1d4))", ')'),
+            ("2d6+3*2", '3'),
+            ("max(1d6,1d8 x)", 'x'),
+            ("1d4-max(1d6,1d8)", '-'),
+        ] {
+            let err = parse(input).unwrap_err();
+            assert!(
+                matches!(err.downcast_ref::<Error>(), Some(Error::UnexpectedChar(found, _)) if found == c),
+                "{}: {}",
+                input,
+                err
+            );
+        }
+        assert_eq!(
+            "Unexpected character 'f' in \"1d6 foo\"",
+            parse("1d6 foo").unwrap_err().to_string()
+        );
+        assert_eq!(vec![2, -1], parse(" 1d6 + 2 - 1d4 -\t1").unwrap().constants);
+        assert_eq!(vec![5], parse(" 5 ").unwrap().constants);
+        assert!(parse("1d6+2 3").is_err());
+        assert!(parse("max(1d6,1d8)-1d4+2dGG[a,b]").is_ok());
+    }
+
+    #[test]
+    fn test_max_min_choose() {
+        let (_, mut rng) = seeded_rng(Some(7));
+        for _ in 0..50 {
+            let res = parse("max(1d20,1d12)").unwrap().cast(1, &mut rng).unwrap();
+            let f = &res.functions[0];
+            let (a, b) = (f.values[0].total, f.values[1].total);
+            assert_eq!(a.max(b), f.value);
+            assert_eq!(f.value, f.values[f.chosen].total);
+            assert_eq!(f.value, res.total);
+
+            let res = parse("min(1d20,1d12)+2")
+                .unwrap()
+                .cast(1, &mut rng)
+                .unwrap();
+            let f = &res.functions[0];
+            let (a, b) = (f.values[0].total, f.values[1].total);
+            assert_eq!(a.min(b), f.value);
+            assert_eq!(f.value + 2, res.total);
+        }
+    }
+
+    #[test]
+    fn test_serde_shape() {
+        let json = serde_json::to_string(&parse("4d6dl1+1d8!+2").unwrap()).unwrap();
+        assert_eq!(
+            r#"{"dice":[{"count":4,"die":{"sides":6},"keep":{"drop_lowest":1}},{"count":1,"die":{"sides":8},"explode":"each"}],"constants":[2],"functions":[]}"#,
+            json
+        );
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let roll = parse("max(3u6,2d4w[1,2,3,4]kh1)+4d6!hdl1+2").unwrap();
+        let json = serde_json::to_string(&roll).unwrap();
+        assert_eq!(roll, serde_json::from_str::<Roll>(&json).unwrap());
+
+        let (_, mut rng) = seeded_rng(Some(6));
+        let res = roll.cast(2, &mut rng).unwrap();
+        let json = serde_json::to_string(&res).unwrap();
+        assert_eq!(res, serde_json::from_str::<RollResult>(&json).unwrap());
+    }
+
+    #[test]
+    fn test_negative_modifiers() {
+        assert_eq!(vec![-2], parse("1d6-2").unwrap().constants);
+        assert_eq!(vec![2, 3], parse("1d4+2+3").unwrap().constants);
+        assert_eq!(vec![-1, 4, -2], parse("1d8-1+2d6+4-2").unwrap().constants);
+        assert_eq!(-2, parse("3d6-per2").unwrap().dice[0].per_die);
+        assert!(parse("3d6-per2").unwrap().constants.is_empty());
+
+        let (_, mut rng) = seeded_rng(Some(1));
+        let res = parse("1d6-2").unwrap().cast(1, &mut rng).unwrap();
+        assert_eq!(res.groups[0].subtotal() - 2, res.total);
+
+        let json: serde_json::Value = serde_json::to_value(&res).unwrap();
+        assert_eq!(serde_json::json!([-2]), json["constants"]);
+        assert_eq!(-2, json["modifier_total"]);
+    }
+
+    #[test]
+    fn test_subtract_dice() {
+        let roll = parse("5-2d1").unwrap();
+        assert!(roll.dice[0].negative);
+        assert_eq!("-2d1+5", roll.to_string());
+        let (_, mut rng) = seeded_rng(Some(1));
+        let res = roll.cast(1, &mut rng).unwrap();
+        assert_eq!(-2, res.groups[0].subtotal());
+        assert_eq!(3, res.total);
+
+        let roll = parse("1d6 - 1d4+max(1d2,1d3)-1d1").unwrap();
+        let signs: Vec<bool> = roll.dice.iter().map(|g| g.negative).collect();
+        assert_eq!(vec![false, true, true], signs);
+        assert_eq!("1d6-1d4-1d1+max(1d2,1d3)", roll.to_string());
+        assert_eq!(roll, parse(&roll.to_string()).unwrap());
+        assert!(!parse("3d6-l1-per1").unwrap().dice[0].negative);
+        assert_eq!(1.0, parse("1d6-1d4").unwrap().expected_value());
+
+        for _ in 0..50 {
+            let res = parse("10-1d4").unwrap().cast(2, &mut rng).unwrap();
+            assert!((2..=8).contains(&res.total), "{}", res.total);
+        }
+    }
+
+    #[test]
+    fn test_missing_operator() {
+        for input in &[
+            "2d6 3d6",
+            "1d6 max(1d4)",
+            "max(1d4)1d6",
+            "2dAB[x,y] 1d6",
+            "2d6 3d6+1",
+        ] {
+            let err = parse(input).unwrap_err();
+            assert!(
+                matches!(err.downcast_ref::<Error>(), Some(Error::MissingOperator(_))),
+                "{}: {}",
+                input,
+                err
+            );
+        }
+        assert!(matches!(
+            parse("1d6 2").unwrap_err().downcast_ref::<Error>(),
+            Some(Error::UnexpectedChar('2', _))
+        ));
+        for input in &["++3", "--1d6", "+-3"] {
+            let err = parse(input).unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref::<Error>(),
+                    Some(Error::UnexpectedChar(_, _))
+                ),
+                "{}: {}",
+                input,
+                err
+            );
+        }
+        assert_eq!(vec![3], parse("+3").unwrap().constants);
+        assert!(parse("-1d6").unwrap().dice[0].negative);
+    }
+
+    #[test]
+    fn test_doubled_signs() {
+        assert_eq!(vec![10, -2], parse("10-+2").unwrap().constants);
+        assert_eq!(vec![10, 2], parse("10--2").unwrap().constants);
+        assert_eq!("1d6+2", parse("1d6--2").unwrap().to_string());
+        assert_eq!("1d6+1d4", parse("1d6 - -1d4").unwrap().to_string());
+        assert_eq!("1d6-1d4", parse("1d6+-1d4").unwrap().to_string());
+        let roll = parse("1d1--1d1").unwrap();
+        assert!(roll.dice.iter().all(|g| !g.negative));
+        assert_eq!("1d1+1d1", roll.to_string());
+
+        for (input, c) in &[
+            ("1d6+", '+'),
+            ("1d6-", '-'),
+            ("1d6 - ", '-'),
+            ("1d6+++2", '+'),
+            ("10---2", '-'),
+            ("1d6-+-1d4", '-'),
+        ] {
+            let err = parse(input).unwrap_err();
+            assert!(
+                matches!(err.downcast_ref::<Error>(), Some(Error::UnexpectedChar(found, _)) if found == c),
+                "{}: {}",
+                input,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_unicode() {
+        assert_eq!(parse("1d6-2").unwrap(), parse("1d6\u{2212}2").unwrap());
+        assert_eq!(parse("1d6-2").unwrap(), parse("1d6\u{2013}2").unwrap());
+        for (input, bad) in &[("1d6+\u{0662}", '\u{0662}'), ("2d6\u{ff0b}3", '\u{ff0b}')] {
+            match parse(input).unwrap_err().downcast_ref::<Error>() {
+                Some(Error::UnexpectedChar(c, _)) => assert_eq!(bad, c),
+                err => panic!("Expected UnexpectedChar for {:?}, got {:?}", input, err),
+            }
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        for input in &[
+            "3d4+2d8+6",
+            "4d6dl1+2d20kh1",
+            "1d6w[1,1,1,1,1,3]!",
+            "3u6+3d6!hklnz-per2",
+            "2d6r[1,5]+1d8w[1,2,1,1,1,1,1,1]r[2]!",
+            "3d6!c18kh2+1d10!hc25",
+            "3d6-maxkh2+2u4-max-2",
+            "4dF+1dFkh1+per1+1",
+            "1d6+2dFFG[blank,success,advantage]+1dBOOST[a,b]+2",
+            "1d6+max(1d20,min(1d12,1d8))+avg(2d8)+5",
+            "best(3,4d6dl1)+crit(1d8)",
+            "1d6-2+3",
+            "+3",
+        ] {
+            let roll = parse(input).unwrap();
+            assert_eq!(*input, roll.to_string());
+            assert_eq!(roll, parse(&roll.to_string()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_const_alone() {
+        let input = "+3";
+        let caps = CONSTANTS.captures(input).unwrap();
+        assert_eq!("3", &caps["const"]);
+    }
+
+    #[test]
+    fn test_constants_only() {
+        let (_, mut rng) = seeded_rng(Some(1));
+        for (input, total) in &[("+5", 5), ("3", 3), ("5+3", 8), ("12-2+1", 11), ("-4", -4)] {
+            let roll = parse(input).unwrap();
+            assert!(roll.dice.is_empty(), "{}", input);
+            let res = roll.cast(1, &mut rng).unwrap();
+            assert!(res.is_constant());
+            assert_eq!(*total, res.total, "{}", input);
+            assert_eq!(roll, parse(&roll.to_string()).unwrap());
+        }
+        assert_eq!(vec![2], parse("2+1d6").unwrap().constants);
+        assert_eq!(Vec::<i32>::new(), parse("3d6").unwrap().constants);
+        assert_eq!(vec![1], parse("10d10+1").unwrap().constants);
+        assert!(!parse("1d6")
+            .unwrap()
+            .cast(1, &mut rng)
+            .unwrap()
+            .is_constant());
+
+        assert_eq!("3\n", roll_verbose("3", &mut rng).unwrap());
+        let dist = parse("+5").unwrap().distribution(1, 1000).unwrap();
+        assert_eq!((5, 5, 5), (dist.min(), dist.max(), dist.mode()));
+    }
+
+    #[test]
+    fn test_dice_regex() {
+        let input = "3d4";
+        let caps = DICE.captures(input).unwrap();
+        assert_eq!("3", &caps["count"]);
+        assert_eq!("d4", &caps["dtype"]);
+    }
+
+    #[test]
+    fn test_many_dice_regex() {
+        let input = "33d100";
+        let caps = DICE.captures(input).unwrap();
+        assert_eq!("33", &caps["count"]);
+        assert_eq!("d100", &caps["dtype"]);
+    }
+
+    #[test]
+    fn test_const_regex() {
+        let input = "3d4+6";
+        let dcaps = DICE.captures(input).unwrap();
+        assert_eq!("3", &dcaps["count"]);
+        assert_eq!("d4", &dcaps["dtype"]);
+
+        let ccaps = CONSTANTS.captures(input).unwrap();
+        assert_eq!("6", &ccaps["const"]);
+
+        let ccaps = CONSTANTS.captures("3d4 - 6").unwrap();
+        assert_eq!("-", &ccaps["sign"]);
+        assert_eq!("6", &ccaps["const"]);
+    }
+
+    #[test]
+    fn test_multiple_dice_regex() {
+        let input = "3d4+2d8";
+        let mut caps = DICE.captures_iter(input);
+        let c1 = &caps.next().unwrap();
+        let c2 = &caps.next().unwrap();
+
+        assert_eq!("3", &c1["count"]);
+        assert_eq!("d4", &c1["dtype"]);
+
+        assert_eq!("2", &c2["count"]);
+        assert_eq!("d8", &c2["dtype"]);
+    }
+
+    #[test]
+    fn test_multiple_const_regex() {
+        let input = "3d4+6+2d8+9";
+        let mut caps = DICE.captures_iter(input);
+        let d1 = &caps.next().unwrap();
+        let d2 = &caps.next().unwrap();
+
+        assert_eq!("3", &d1["count"]);
+        assert_eq!("d4", &d1["dtype"]);
+
+        assert_eq!("2", &d2["count"]);
+        assert_eq!("d8", &d2["dtype"]);
+
+        let mut ccaps = CONSTANTS.captures_iter(input);
+        let c1 = &ccaps.next().unwrap();
+        let c2 = &ccaps.next().unwrap();
+
+        assert_eq!("6", &c1["const"]);
+        assert_eq!("9", &c2["const"]);
+    }
+}
@@ -1,224 +1,2114 @@
-use error::Result;
-use once_cell::sync::Lazy;
-use rand::Rng;
-use regex::Regex;
+use rand::{Rng, RngCore};
+use roller::analysis::{
+    histogram, largest_match, longest_run, Distribution, Query, DEFAULT_FACES_LIMIT,
+};
+use roller::error::{bail, Context, Error, Result};
+use roller::foundry::FoundryRoll;
+use roller::replay::ReplayToken;
+use roller::table::Table;
+use roller::{crit_chance, parse, session_rng, split_exprs, Die, Keep, RngKind, Roll, RollResult};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, IsTerminal, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 use structopt::StructOpt;
 
-mod error {
-    pub use anyhow::{Context, Result};
-    use thiserror::Error;
-
-    #[derive(Error, Debug)]
-    pub enum Error {}
-}
-
-#[derive(Debug, StructOpt)]
+#[derive(Clone, Debug, StructOpt)]
 #[structopt(name = "roller", about = "A simple die roller")]
 struct Opt {
-    pub input: String,
+    /// The expressions to roll [default: $ROLLER_DEFAULT]
+    pub input: Vec<String>,
     #[structopt(short, long)]
     pub crit: bool,
+    /// Roll this expression for the crit multiplier instead of doubling, as in `1d4` for x1
+    /// to x4; each roll gets its own multiplier
+    #[structopt(long, requires = "crit")]
+    pub crit_mult_die: Option<String>,
+    /// Seed for the RNG, to reproduce an earlier roll
+    #[structopt(short, long)]
+    pub seed: Option<u64>,
+    /// The RNG to roll with: thread (fast, the default), secure (every number from the OS,
+    /// slower) or seeded (reproducible, and implied by --seed and --verbose)
+    #[structopt(long)]
+    pub rng: Option<RngKind>,
+    /// Print extra detail, such as the seed used; without --rng this rolls with the seeded RNG
+    /// so that there is a seed to print
+    #[structopt(short, long)]
+    pub verbose: bool,
+    /// Print the average total, as listed in monster stat blocks, instead of rolling
+    #[structopt(long)]
+    pub monster_average: bool,
+    /// The lowest natural d20 roll that counts as a crit
+    #[structopt(long, default_value = "20")]
+    pub crit_range: u32,
+    /// Print the chance of a natural crit for the given --crit-range
+    #[structopt(long)]
+    pub crit_chance: bool,
+    /// Roll the expression this many times
+    #[structopt(short, long, default_value = "1")]
+    pub times: u32,
+    /// Print only the total of each roll, one per line
+    #[structopt(long, visible_aliases = &["quiet", "sum-only"])]
+    pub compact: bool,
+    /// Add this to the total of every expression, after any crit multiplier
+    #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+    pub add: i32,
+    /// Write output to this file instead of stdout
+    #[structopt(short, long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+    /// Append to the --output file rather than overwriting it
+    #[structopt(long, requires = "output")]
+    pub append: bool,
+    /// Roll two expressions against each other and report the winner and margin
+    #[structopt(long)]
+    pub opposed: bool,
+    /// Compare two expressions side by side: their mean, min and max, and how often each
+    /// rolls higher
+    #[structopt(long, conflicts_with = "opposed")]
+    pub compare: bool,
+    /// Report the largest set of dice showing the same value
+    #[structopt(long)]
+    pub count_matches: bool,
+    /// Print how many dice showed each value, as in 1:3 2:1 4:2
+    #[structopt(long)]
+    pub histogram: bool,
+    /// Print totals with thousands separators, as in 1,234,567, except in --compact or --json
+    /// output
+    #[structopt(long)]
+    pub group_digits: bool,
+    /// Print the exact chance of every possible total instead of rolling
+    #[structopt(long)]
+    pub stats_detailed: bool,
+    /// The most outcomes --stats-detailed will enumerate for keep/drop or unique dice
+    /// [default: 1000000]
+    #[structopt(long)]
+    pub faces_limit: Option<u64>,
+    /// Print what would be rolled and its expected value, without rolling
+    #[structopt(long)]
+    pub dry: bool,
+    /// Print each expression in its canonical form, with like dice combined and terms
+    /// sorted, without rolling
+    #[structopt(long)]
+    pub normalize: bool,
+    /// Make a Savage Worlds trait roll with this die, such as d8, alongside an exploding d6
+    /// wild die
+    #[structopt(long, conflicts_with = "input")]
+    pub savage: Option<String>,
+    /// The line printed between the dice and the total
+    #[structopt(long, default_value = "---", allow_hyphen_values = true)]
+    pub separator: String,
+    /// Print the total straight after the dice, with no separator line
+    #[structopt(long)]
+    pub no_separator: bool,
+    /// Print each roll as a line of JSON
+    #[structopt(long)]
+    pub json: bool,
+    /// Don't print "Critical Hit!" when rolling with --crit
+    #[structopt(long)]
+    pub no_banner: bool,
+    /// The total a roll needs to reach to succeed, such as an armour class
+    #[structopt(long, allow_hyphen_values = true)]
+    pub target: Option<i32>,
+    /// Roll an attack then its damage, confirming a natural crit with a second attack roll
+    /// against --target before doubling the damage
+    #[structopt(long, requires = "target", conflicts_with = "crit")]
+    pub confirm_crit: bool,
+    /// Look each total up in this table file of lines like `1-3: Goblins`
+    #[structopt(long, parse(from_os_str))]
+    pub table: Option<PathBuf>,
+    /// Print each roll as a line of JSON, with a step-by-step narration of how the total
+    /// was reached
+    #[structopt(long, conflicts_with = "json")]
+    pub explain_json: bool,
+    /// Print each roll for another tool to import; `foundry` gives Foundry VTT's chat roll JSON
+    #[structopt(long, conflicts_with_all = &["json", "explain-json"])]
+    pub format: Option<Format>,
+    /// Leave dice showing 0, such as blank Fudge dice, out of the per-die lines; the total
+    /// is unchanged
+    #[structopt(long)]
+    pub hide_zeros: bool,
+    /// Name an expression so it can be rolled by name, as in --alias fireball=8d6;
+    /// may be repeated
+    #[structopt(long, number_of_values = 1)]
+    pub alias: Vec<String>,
+    /// Print the lowest, highest, mean and most likely totals instead of rolling
+    #[structopt(long)]
+    pub stats: bool,
+    /// Reveal each die and the total after a short spinner; only on a terminal
+    #[structopt(long)]
+    pub animate: bool,
+    /// How long --animate spends on each reveal, in milliseconds
+    #[structopt(long, default_value = "400")]
+    pub animate_delay: u64,
+    /// Report the longest run of consecutive values among the dice, as in a straight
+    #[structopt(long)]
+    pub longest_run: bool,
+    /// Keep a running total of every roll in this file across runs
+    #[structopt(long, parse(from_os_str))]
+    pub session: Option<PathBuf>,
+    /// Start the --session total again from zero
+    #[structopt(long, requires = "session")]
+    pub session_reset: bool,
+    /// Check that no expression can total more than this, failing if one can
+    #[structopt(long, allow_hyphen_values = true)]
+    pub max_budget: Option<i32>,
+    /// Also print half of each total, rounded down, as the damage on a successful save
+    #[structopt(long)]
+    pub with_half: bool,
+    /// Print each dice group's total on its own line instead of combining them
+    #[structopt(long, conflicts_with_all = &["compact", "json", "explain-json", "format"])]
+    pub split_groups: bool,
+    /// Roll oversized pools anyway, capped at the most dice that can be rolled at once and
+    /// without explosions, with a warning instead of an error
+    #[structopt(long)]
+    pub best_effort: bool,
+    /// Roll each expression again and again, adding up the totals, until they reach this;
+    /// then print how many rolls it took
+    #[structopt(long, allow_hyphen_values = true)]
+    pub roll_until: Option<i32>,
+    /// Print a token that reproduces these rolls with --replay
+    #[structopt(long)]
+    pub replay_token: bool,
+    /// Roll the expressions of a --replay-token again, with its seed and any options it
+    /// recorded that change the totals, such as --add; those given again are ignored
+    #[structopt(long, conflicts_with_all = &["input", "seed", "rng"])]
+    pub replay: Option<String>,
+    /// The least any die can count for after its per-die bonus or penalty, before keep/drop
+    #[structopt(long, allow_hyphen_values = true)]
+    pub floor_die: Option<i32>,
+    /// Hold every die within LOW:HIGH after its per-die bonus, whatever its size
+    #[structopt(long, parse(try_from_str = parse_clamp), allow_hyphen_values = true)]
+    pub clamp_die: Option<RangeInclusive<i32>>,
+    /// List the dice by die type, largest first, each highest first; the total is unchanged
+    #[structopt(long, conflicts_with_all = &["split-groups", "explain-json", "format"])]
+    pub group_sort: bool,
+    /// Grade each total against this target: failure, partial success, success or critical
+    /// success
+    #[structopt(long, allow_hyphen_values = true)]
+    pub tiers: Option<i32>,
+    /// Roll each lone d20 twice and keep the higher
+    #[structopt(long, conflicts_with = "disadvantage")]
+    pub advantage: bool,
+    /// Roll each lone d20 twice and keep the lower
+    #[structopt(long)]
+    pub disadvantage: bool,
 }
 
-static DICE: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"(?x)(?P<count>\d+)(?P<dtype>d\d+)\+?").expect("Failed to compile Dice Regex")
-});
+/// The environment variable holding the expression to roll when none is given.
+const DEFAULT_VAR: &str = "ROLLER_DEFAULT";
+
+impl Opt {
+    /// Falls back to `default` when no expression was given on the command line.
+    fn with_default(mut self, default: Option<String>) -> Self {
+        if let (true, Some(default)) = (self.input.is_empty(), default) {
+            self.input.push(default);
+        }
+        self
+    }
+
+    fn faces_limit(&self) -> u64 {
+        self.faces_limit.unwrap_or(DEFAULT_FACES_LIMIT)
+    }
+
+    /// Whether output is for a script or another program rather than a person.
+    fn scripted(&self) -> bool {
+        self.compact || self.json || self.explain_json || self.format.is_some()
+    }
+
+    /// Whether to announce a crit; scripted output modes stay clean.
+    fn banner(&self) -> bool {
+        self.crit && !(self.no_banner || self.scripted())
+    }
+
+    fn aliases(&self) -> Result<HashMap<&str, &str>> {
+        self.alias
+            .iter()
+            .map(|alias| match alias.split_once('=') {
+                Some((name, expr)) => Ok((name.trim(), expr.trim())),
+                None => bail!("Invalid alias {:?}: expected NAME=EXPR", alias),
+            })
+            .collect()
+    }
+
+    /// Whether to animate: never into a file or a pipe, or in the scripted output modes.
+    fn animating(&self) -> bool {
+        self.animate && !self.scripted() && self.output.is_none() && io::stdout().is_terminal()
+    }
 
-static CONSTANTS: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"\+(?P<const>\d+)(\+|$)").expect("Failed to compile Constants Regex"));
+    /// The RNG for this run, and its seed if it has one.
+    fn rng(&self) -> Result<(Option<u64>, Box<dyn RngCore>)> {
+        let seeded = self.seed.is_some() || self.replay_token;
+        let kind = match (self.rng, seeded) {
+            (Some(RngKind::Seeded), _) | (None, true) => RngKind::Seeded,
+            (Some(kind), true) => bail!(
+                "--seed and --replay-token only apply to --rng seeded, not {:?}",
+                kind
+            ),
+            (Some(kind), false) => kind,
+            // With no RNG chosen, --verbose rolls with a seed so that it can print it.
+            (None, false) if self.verbose => RngKind::Seeded,
+            (None, false) => RngKind::Thread,
+        };
+        Ok(session_rng(kind, self.seed))
+    }
+
+    /// The options that change what the expressions total, as arguments for a replay token.
+    fn roll_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.times != 1 {
+            args.push(format!("--times={}", self.times));
+        }
+        if self.add != 0 {
+            args.push(format!("--add={}", self.add));
+        }
+        if let Some(floor) = self.floor_die {
+            args.push(format!("--floor-die={}", floor));
+        }
+        if let Some(range) = &self.clamp_die {
+            args.push(format!("--clamp-die={}:{}", range.start(), range.end()));
+        }
+        let flags = [
+            (self.crit, "--crit"),
+            (self.advantage, "--advantage"),
+            (self.disadvantage, "--disadvantage"),
+            (self.best_effort, "--best-effort"),
+        ];
+        for (set, flag) in flags.iter() {
+            if *set {
+                args.push(flag.to_string());
+            }
+        }
+        args
+    }
 
-#[derive(Clone, Debug, PartialEq)]
-enum Dice {
-    D4,
-    D6,
-    D8,
-    D10,
-    D12,
-    D20,
-    D100,
+    /// These options with the expressions and `roll_args` of `other` in place of their own.
+    fn with_roll_args(&self, other: &Opt) -> Opt {
+        Opt {
+            input: other.input.clone(),
+            times: other.times,
+            add: other.add,
+            floor_die: other.floor_die,
+            clamp_die: other.clamp_die.clone(),
+            crit: other.crit,
+            advantage: other.advantage,
+            disadvantage: other.disadvantage,
+            best_effort: other.best_effort,
+            ..self.clone()
+        }
+    }
+
+    /// A total as printed; scripted output keeps it raw even with --group-digits.
+    fn total(&self, total: i32) -> String {
+        match self.group_digits && !self.scripted() {
+            true => group_digits(total),
+            false => total.to_string(),
+        }
+    }
+}
+
+fn parse_clamp(input: &str) -> Result<RangeInclusive<i32>> {
+    let bounds = input.split_once(':').and_then(|(low, high)| {
+        let low: i32 = low.trim().parse().ok()?;
+        Some((low, high.trim().parse::<i32>().ok()?))
+    });
+    match bounds {
+        Some((low, high)) if low > high => Err(Error::InvalidClamp { low, high }.into()),
+        Some((low, high)) => Ok(low..=high),
+        None => bail!("Invalid clamp {:?}: expected LOW:HIGH, as in 2:5", input),
+    }
+}
+
+/// Output formats for importing rolls into other tools.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Foundry,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        match input {
+            "foundry" => Ok(Format::Foundry),
+            _ => bail!("Unknown format {:?}: expected foundry", input),
+        }
+    }
 }
 
-impl From<&str> for Dice {
-    fn from(s: &str) -> Self {
-        match s {
-            "d4" => Dice::D4,
-            "d6" => Dice::D6,
-            "d8" => Dice::D8,
-            "d10" => Dice::D10,
-            "d12" => Dice::D12,
-            "d20" => Dice::D20,
-            "d100" => Dice::D100,
-            _ => unreachable!(),
+fn group_digits(n: i32) -> String {
+    let digits = n.unsigned_abs().to_string();
+    let mut grouped = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(',');
         }
+        grouped.push(c);
+    }
+    match n < 0 {
+        true => format!("-{}", grouped),
+        false => grouped,
+    }
+}
+
+/// The target number for a Savage Worlds trait roll; every 4 over it is a raise.
+const SAVAGE_TARGET: i32 = 4;
+
+/// The dice a Savage Worlds trait can be rated in.
+const SAVAGE_DICE: [u32; 5] = [4, 6, 8, 10, 12];
+
+fn savage_roll(trait_die: &str, add: i32) -> Result<Roll> {
+    let sides = trait_die
+        .strip_prefix("1d")
+        .or_else(|| trait_die.strip_prefix('d'))
+        .unwrap_or(trait_die);
+    match SAVAGE_DICE.iter().find(|n| n.to_string() == sides) {
+        Some(sides) => prepare(&format!("max(1d{}!,1d6!)", sides), add),
+        None => bail!(
+            "Invalid trait die {:?}: expected one of d4, d6, d8, d10 or d12",
+            trait_die
+        ),
+    }
+}
+
+/// Rolls each lone d20 of a roll twice, keeping the higher or lower as `keep` says.
+fn with_advantage(input: &str, roll: &Roll, keep: Keep) -> Result<Roll> {
+    let mut roll = roll.clone();
+    let mut lone = roll
+        .dice
+        .iter_mut()
+        .filter(|g| g.count == 1 && g.die == Die::new(20) && g.keep.is_none())
+        .peekable();
+    if lone.peek().is_none() {
+        bail!("{} has no lone d20 to roll twice", input);
+    }
+    for group in lone {
+        group.count = 2;
+        group.keep = Some(keep.clone());
+    }
+    Ok(roll)
+}
+
+fn savage_outcome(total: i32) -> String {
+    match (total - SAVAGE_TARGET) / SAVAGE_TARGET {
+        _ if total < SAVAGE_TARGET => "Failure".to_string(),
+        0 => "Success".to_string(),
+        1 => "Success with 1 raise".to_string(),
+        raises => format!("Success with {} raises", raises),
+    }
+}
+
+/// How far under the target a --tiers roll can fall and still be a partial success.
+const PARTIAL_MARGIN: i32 = 3;
+
+/// How far over the target a --tiers roll must go to be a critical success.
+const CRITICAL_MARGIN: i32 = 5;
+
+fn tier(total: i32, target: i32) -> &'static str {
+    match total - target {
+        margin if margin >= CRITICAL_MARGIN => "Critical success",
+        margin if margin >= 0 => "Success",
+        margin if margin >= -PARTIAL_MARGIN => "Partial success",
+        _ => "Failure",
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args().with_default(std::env::var(DEFAULT_VAR).ok());
+
+    let result = output(&opt).and_then(|mut out| run(&opt, &mut out));
+    let code = exit_code(result, &mut io::stderr());
+    if code != 0 {
+        std::process::exit(code);
     }
 }
 
-impl From<Dice> for i32 {
-    fn from(d: Dice) -> Self {
-        let mut rng = rand::thread_rng();
-        match d {
-            Dice::D4 => rng.gen_range(1, 5),
-            Dice::D6 => rng.gen_range(1, 7),
-            Dice::D8 => rng.gen_range(1, 9),
-            Dice::D10 => rng.gen_range(1, 11),
-            Dice::D12 => rng.gen_range(1, 13),
-            Dice::D20 => rng.gen_range(1, 21),
-            Dice::D100 => rng.gen_range(1, 101),
+/// Reports a failed run on `err`, and returns the exit code for it: nonzero on any error, so
+/// scripts such as a --max-budget check can tell a pass from a failure.
+fn exit_code<W: Write + ?Sized>(result: Result<()>, err: &mut W) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            let _ = writeln!(err, "{:#}", e);
+            1
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
-struct Roll {
-    pub dice: Vec<Dice>,
-    pub constants: Vec<i32>,
+fn output(opt: &Opt) -> Result<Box<dyn Write>> {
+    Ok(match &opt.output {
+        Some(path) => Box::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(opt.append)
+                .truncate(!opt.append)
+                .open(path)
+                .with_context(|| format!("Failed to open {}", path.display()))?,
+        ),
+        None => Box::new(io::stdout()),
+    })
 }
 
-impl Roll {
-    pub fn new() -> Self {
-        Roll {
-            dice: Vec::new(),
-            constants: Vec::new(),
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+/// Writes a line of output, first spinning in its place when animating.
+fn reveal<W: Write + ?Sized>(out: &mut W, opt: &Opt, line: &str) -> Result<()> {
+    if opt.animating() {
+        let frame = Duration::from_millis(opt.animate_delay) / SPINNER.len() as u32;
+        for c in SPINNER.iter() {
+            write!(out, "\r{}", c)?;
+            out.flush()?;
+            thread::sleep(frame);
         }
+        write!(out, "\r")?;
     }
+    writeln!(out, "{}", line)?;
+    Ok(())
+}
 
-    pub fn cast(self, crit: i32) -> i32 {
-        let dice_scores: Vec<i32> = self.dice.into_iter().map(Into::<i32>::into).collect();
-        for n in &dice_scores {
-            println!("{}", n);
+fn print_result<W: Write + ?Sized>(out: &mut W, opt: &Opt, res: &RollResult) -> Result<()> {
+    for group in &res.groups {
+        for roll in &group.rolls {
+            if !(opt.hide_zeros && roll.value == 0) {
+                reveal(out, opt, &roll.to_string())?;
+            }
         }
-        let dice: i32 = dice_scores.iter().sum();
-        let constant: i32 = self.constants.iter().sum();
+    }
+    for function in &res.functions {
+        writeln!(out, "{}", function)?;
+    }
+    for line in res.symbol_lines() {
+        writeln!(out, "{}", line)?;
+    }
+    if opt.verbose && res.explosions() > 0 {
+        writeln!(
+            out,
+            "Explosions: {} (longest chain: {})",
+            res.explosions(),
+            res.longest_chain()
+        )?;
+    }
+    if opt.verbose {
+        let dice = |n| match n {
+            1 => "1 die".to_string(),
+            n => format!("{} dice", n),
+        };
+        match res.max_count() {
+            0 => {}
+            1 => writeln!(out, "1 die rolled its max!")?,
+            n => writeln!(out, "{} rolled their max!", dice(n))?,
+        }
+        match res.min_count() {
+            0 => {}
+            1 => writeln!(out, "1 die rolled a 1")?,
+            n => writeln!(out, "{} rolled a 1", dice(n))?,
+        }
+    }
+    if opt.count_matches {
+        if let Some((value, count)) = largest_match(&res.values()) {
+            writeln!(out, "Largest match: {} x{}", value, count)?;
+        }
+    }
+    if opt.histogram {
+        let counts: Vec<String> = histogram(&res.values())
+            .iter()
+            .map(|(value, count)| format!("{}:{}", value, count))
+            .collect();
+        writeln!(out, "Histogram: {}", counts.join(" "))?;
+    }
+    if opt.longest_run {
+        if let Some(run) = longest_run(&res.values()) {
+            let run: Vec<String> = run.iter().map(i32::to_string).collect();
+            writeln!(out, "Longest run: {} ({})", run.join(", "), run.len())?;
+        }
+    }
+    Ok(())
+}
 
-        (dice * crit) + constant
+/// Writes the total, after a separator unless there were no dice above it.
+fn print_total<W: Write + ?Sized>(out: &mut W, opt: &Opt, res: &RollResult) -> Result<()> {
+    let total = res.total;
+    if !(opt.no_separator || res.is_constant()) {
+        writeln!(out, "{}", opt.separator)?;
+    }
+    reveal(out, opt, &opt.total(total))?;
+    if opt.with_half {
+        writeln!(out, "On save: {}", opt.total(half(total)))?;
     }
+    Ok(())
 }
 
-fn main() {
-    let opt = Opt::from_args();
-    let crit = match opt.crit {
-        true => {
-            println!("Critical Hit!");
-            2
+/// Half of a total, rounded down.
+fn half(total: i32) -> i32 {
+    total.div_euclid(2)
+}
+
+fn print_groups<W: Write + ?Sized>(
+    out: &mut W,
+    opt: &Opt,
+    roll: &Roll,
+    res: &RollResult,
+    crit: i32,
+) -> Result<()> {
+    let mult = roll.untagged_crit(crit);
+    for (group, result) in roll.dice.iter().zip(&res.groups) {
+        writeln!(out, "{}: {}", group, opt.total(result.subtotal() * mult))?;
+    }
+    for (function, result) in roll.functions.iter().zip(&res.functions) {
+        writeln!(out, "{}: {}", function, opt.total(result.value))?;
+    }
+    if !res.constants.is_empty() {
+        let modifiers: Vec<String> = res.constants.iter().map(|c| format!("{:+}", c)).collect();
+        writeln!(out, "Modifiers: {}", modifiers.join(" "))?;
+    }
+    Ok(())
+}
+
+/// How many times --roll-until rolls before deciding the target can't be reached.
+const MAX_ROLLS_UNTIL: u32 = 10_000;
+
+fn print_roll_until<W: Write + ?Sized, R: Rng + ?Sized>(
+    out: &mut W,
+    opt: &Opt,
+    roll: &Roll,
+    target: i32,
+    crit: i32,
+    rng: &mut R,
+) -> Result<()> {
+    roll.validate()?;
+    let mut total = 0;
+    for rolls in 1..=MAX_ROLLS_UNTIL {
+        total += roll.cast(crit, rng)?.total;
+        if total >= target {
+            let rolls = match rolls {
+                1 => "1 roll".to_string(),
+                n => format!("{} rolls", n),
+            };
+            writeln!(out, "Reached {} in {}", opt.total(total), rolls)?;
+            return Ok(());
         }
-        false => 1,
-    };
+    }
+    bail!(
+        "{} did not reach {} in {} rolls",
+        roll,
+        target,
+        MAX_ROLLS_UNTIL
+    )
+}
+
+fn print_opposed<W: Write + ?Sized>(out: &mut W, opt: &Opt, results: &[RollResult]) -> Result<()> {
+    let inputs = &opt.input;
+    for (input, res) in inputs.iter().zip(results) {
+        writeln!(out, "{}: {}", input, opt.total(res.total))?;
+    }
+
+    let (a, b) = (results[0].total, results[1].total);
+    match a.cmp(&b) {
+        Ordering::Greater => writeln!(out, "{} wins by {}", inputs[0], opt.total(a - b))?,
+        Ordering::Less => writeln!(out, "{} wins by {}", inputs[1], opt.total(b - a))?,
+        Ordering::Equal => writeln!(out, "Tie at {}", opt.total(a))?,
+    }
+    Ok(())
+}
+
+/// The natural roll of the first lone d20 in a roll, before any per-die bonus or floor.
+fn natural_d20(roll: &Roll, res: &RollResult) -> Option<i32> {
+    roll.dice
+        .iter()
+        .zip(&res.groups)
+        .find(|(group, _)| group.count == 1 && group.die.sides == 20)
+        .map(|(_, res)| res.rolls[0].natural)
+}
+
+fn print_confirm_crit<W: Write + ?Sized, R: Rng + ?Sized>(
+    out: &mut W,
+    opt: &Opt,
+    rolls: &[Roll],
+    target: i32,
+    rng: &mut R,
+) -> Result<()> {
+    if rolls.len() != 2 {
+        bail!(
+            "--confirm-crit needs an attack and a damage expression, got {}",
+            rolls.len()
+        );
+    }
+    crit_chance(opt.crit_range)?;
+    let (attack, damage) = (&rolls[0], &rolls[1]);
+
+    let res = attack.cast(1, rng)?;
+    let natural = natural_d20(attack, &res)
+        .with_context(|| format!("--confirm-crit needs a 1d20 in {}", opt.input[0]))?;
+    writeln!(
+        out,
+        "Attack: {} (natural {})",
+        opt.total(res.total),
+        natural
+    )?;
+    // A natural 1 always misses and a natural 20 always hits, whatever the total.
+    if natural == 1 || (res.total < target && natural != 20) {
+        writeln!(out, "Miss")?;
+        return Ok(());
+    }
+
+    let mut crit = 1;
+    if natural >= opt.crit_range as i32 {
+        let confirm = attack.cast(1, rng)?;
+        // A natural 1 on the confirmation fails however high the bonus.
+        let confirmed = natural_d20(attack, &confirm) != Some(1) && confirm.total >= target;
+        writeln!(
+            out,
+            "Threat, confirmation: {} ({})",
+            opt.total(confirm.total),
+            match confirmed {
+                true => "confirmed",
+                false => "not confirmed",
+            }
+        )?;
+        if confirmed {
+            crit = 2;
+        }
+    }
+    let res = damage.cast(crit, rng)?;
+    writeln!(out, "Damage: {}", opt.total(res.total))?;
+    Ok(())
+}
+
+/// The running total kept in a --session file.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Session {
+    total: i32,
+    rolls: u32,
+}
+
+impl Session {
+    /// Loads a session, starting from zero if the file doesn't exist yet.
+    fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)
+                .with_context(|| format!("Invalid session file {}", path.display())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Session::default()),
+            Err(err) => Err(err).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// A roll result with its narration, for --explain-json.
+#[derive(Serialize)]
+struct Explained<'a> {
+    #[serde(flatten)]
+    result: &'a RollResult,
+    steps: Vec<String>,
+}
 
-    match run(opt.input, crit) {
-        Ok(res) => println!("---\n{}", res),
-        Err(err) => {
-            println!("{:?}", err);
+/// Describes, in order, how `res` was rolled from `roll`.
+fn explain(roll: &Roll, res: &RollResult, crit: i32) -> Vec<String> {
+    let mut steps = Vec::new();
+    for (group, rolled) in roll.dice.iter().zip(&res.groups) {
+        let dice: Vec<String> = rolled.rolls.iter().map(|d| d.to_string()).collect();
+        steps.push(format!("Rolled {}: {}", group, dice.join(", ")));
+        if rolled.rolls.iter().any(|d| !d.kept) {
+            let kept: Vec<String> = rolled
+                .rolls
+                .iter()
+                .filter(|d| d.kept)
+                .map(|d| d.value.to_string())
+                .collect();
+            steps.push(format!(
+                "Kept {} for {}",
+                kept.join(", "),
+                rolled.subtotal()
+            ));
         }
     }
+    for function in &res.functions {
+        steps.push(format!("Evaluated {}", function));
+    }
+
+    let dice: i32 = res.groups.iter().map(|g| g.subtotal()).sum();
+    let multiplier = roll.untagged_crit(crit);
+    if multiplier > 1 && !res.groups.is_empty() {
+        steps.push(format!(
+            "Crit: dice multiplied by {}, {} to {}",
+            multiplier,
+            dice,
+            dice * multiplier
+        ));
+    }
+    if !res.constants.is_empty() {
+        let constants: Vec<String> = res.constants.iter().map(|c| format!("{:+}", c)).collect();
+        steps.push(format!(
+            "Modifiers {} for {:+}",
+            constants.join(" "),
+            res.modifier_total
+        ));
+    }
+    steps.push(format!("Total: {}", res.total));
+    steps
+}
+
+/// The expression to parse for an input, which may instead name an alias.
+fn resolve<'a>(input: &'a str, aliases: &HashMap<&str, &'a str>) -> Result<&'a str> {
+    let name = input.trim();
+    let is_name = name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    match (aliases.get(name), is_name) {
+        (Some(expr), _) => Ok(expr),
+        (None, true) => bail!(
+            "Unknown alias {:?}: define it with --alias {}=EXPR",
+            name,
+            name
+        ),
+        (None, false) => Ok(input),
+    }
 }
 
-fn run(input: String, crit: i32) -> Result<i32> {
-    let roll = parse(&input)?;
+/// How many rolls --stats samples when a roll is too large to enumerate exactly.
+const STATS_SAMPLES: usize = 10_000;
 
-    Ok(roll.cast(crit))
+/// The roll's distribution, sampled when it's too large to enumerate; the flag says whether
+/// it was sampled.
+fn stats_distribution(opt: &Opt, roll: &Roll, crit: i32) -> Result<(Distribution, bool)> {
+    match roll.distribution(crit, opt.faces_limit()) {
+        Ok(dist) => Ok((dist, false)),
+        Err(err) => match err.downcast_ref::<Error>() {
+            Some(Error::TooManyOutcomes(..)) | Some(Error::NotEnumerable(_)) => {
+                let (_, mut rng) = opt.rng()?;
+                let dist = roll.sample_distribution(crit, &mut rng, STATS_SAMPLES)?;
+                Ok((dist, true))
+            }
+            _ => Err(err),
+        },
+    }
 }
 
-fn parse(input: &str) -> Result<Roll> {
-    let caps = DICE.captures_iter(input);
-    let ccaps = CONSTANTS.captures_iter(input);
-    let mut roll = Roll::new();
+fn print_stats<W: Write + ?Sized>(out: &mut W, opt: &Opt, roll: &Roll, crit: i32) -> Result<()> {
+    let (dist, sampled) = stats_distribution(opt, roll, crit)?;
+    if sampled {
+        writeln!(out, "Estimated from {} rolls", STATS_SAMPLES)?;
+    }
+    write_stats(out, opt, &dist)
+}
 
-    for c in caps {
-        for _ in 0..c["count"].parse::<i32>()? {
-            roll.dice.push(Dice::from(&c["dtype"]))
+fn print_compare<W: Write + ?Sized>(
+    out: &mut W,
+    opt: &Opt,
+    rolls: &[Roll],
+    crit: i32,
+) -> Result<()> {
+    if rolls.len() != 2 {
+        bail!(
+            "--compare needs exactly two expressions, got {}",
+            rolls.len()
+        );
+    }
+    let (a, b) = (&opt.input[0], &opt.input[1]);
+    let mut dists = Vec::with_capacity(2);
+    for (input, roll) in opt.input.iter().zip(rolls) {
+        let (dist, sampled) = stats_distribution(opt, roll, crit)?;
+        if sampled {
+            writeln!(out, "{} estimated from {} rolls", input, STATS_SAMPLES)?;
         }
+        dists.push(dist);
     }
 
-    for c in ccaps {
-        roll.constants.push(c["const"].parse::<i32>()?)
+    let column = |f: &dyn Fn(&Distribution) -> String| (f(&dists[0]), f(&dists[1]));
+    let rows = [
+        ("", (a.to_string(), b.to_string())),
+        ("Mean", column(&|d| format!("{:.2}", d.mean()))),
+        ("Min", column(&|d| opt.total(d.min()))),
+        ("Max", column(&|d| opt.total(d.max()))),
+    ];
+    let width = |side: fn(&(String, String)) -> &String| {
+        rows.iter().map(|(_, r)| side(r).len()).max().unwrap_or(0)
+    };
+    let (left, right) = (width(|r| &r.0), width(|r| &r.1));
+    for (label, (x, y)) in &rows {
+        writeln!(out, "{:<4}  {:>left$}  {:>right$}", label, x, y)?;
     }
 
+    let percent = |chance: f64| format!("{:.2}%", chance * 100.0);
+    writeln!(
+        out,
+        "P({} > {}): {}",
+        a,
+        b,
+        percent(dists[0].chance_above(&dists[1]))
+    )?;
+    writeln!(
+        out,
+        "P({} = {}): {}",
+        a,
+        b,
+        percent(dists[0].chance_tied(&dists[1]))
+    )?;
+    writeln!(
+        out,
+        "P({} < {}): {}",
+        a,
+        b,
+        percent(dists[1].chance_above(&dists[0]))
+    )?;
+    Ok(())
+}
+
+fn write_stats<W: Write + ?Sized>(out: &mut W, opt: &Opt, dist: &Distribution) -> Result<()> {
+    writeln!(out, "Min: {}", opt.total(dist.min()))?;
+    writeln!(out, "Max: {}", opt.total(dist.max()))?;
+    writeln!(out, "Mean: {:.2}", dist.mean())?;
+    writeln!(out, "Std dev: {:.2}", dist.std_dev())?;
+    writeln!(out, "Mode: {}", opt.total(dist.mode()))?;
+    Ok(())
+}
+
+/// Parses an expression, folding in the global `--add` as a flat modifier.
+fn prepare(input: &str, add: i32) -> Result<Roll> {
+    let mut roll = parse(input)?;
+    if add != 0 {
+        roll.constants.push(add);
+    }
     Ok(roll)
 }
 
+/// Applies the options that change how each die rolls: advantage, --best-effort,
+/// --floor-die and --clamp-die, in that order. Queries and rolls share it so a
+/// query's chance matches what rolling would give.
+fn transform(opt: &Opt, input: &str, roll: &Roll) -> Result<Roll> {
+    let roll = match (opt.advantage, opt.disadvantage) {
+        (false, false) => roll.clone(),
+        (advantage, _) => {
+            let keep = match advantage {
+                true => Keep::Highest(1),
+                false => Keep::Lowest(1),
+            };
+            with_advantage(input, roll, keep)?
+        }
+    };
+    let roll = match opt.best_effort {
+        true => {
+            let (roll, warnings) = roll.best_effort();
+            for warning in warnings {
+                eprintln!("Warning: {}", warning);
+            }
+            roll
+        }
+        false => roll,
+    };
+    let roll = match opt.floor_die {
+        Some(floor) => roll.with_floor(floor),
+        None => roll,
+    };
+    Ok(match &opt.clamp_die {
+        Some(range) => roll.with_clamp(range.clone()),
+        None => roll,
+    })
+}
+
+fn print_dry<W: Write + ?Sized>(out: &mut W, roll: &Roll, crit: i32) -> Result<()> {
+    let mut expected = 0.0;
+    for group in &roll.dice {
+        let mean = group.mean() * f64::from(roll.untagged_crit(crit));
+        writeln!(out, "{}: {:.2}", group, mean)?;
+        expected += mean;
+    }
+    for function in &roll.functions {
+        let mean = function.mean(roll.function_crit(function, crit));
+        writeln!(out, "{}: {:.2}", function, mean)?;
+        expected += mean;
+    }
+    let modifier: i32 = roll.constants.iter().sum();
+    if modifier != 0 {
+        writeln!(out, "Modifier: {:+}", modifier)?;
+    }
+    writeln!(out, "Expected: {:.2}", expected + f64::from(modifier))?;
+    Ok(())
+}
+
+fn run<W: Write + ?Sized>(opt: &Opt, out: &mut W) -> Result<()> {
+    if let Some(token) = &opt.replay {
+        let token: ReplayToken = token.parse()?;
+        let args = std::iter::once("roller".to_string())
+            .chain(token.args)
+            .chain(std::iter::once("--".to_string()))
+            .chain(token.exprs);
+        let replayed = Opt::from_iter_safe(args)?;
+        let opt = Opt {
+            seed: Some(token.seed),
+            replay: None,
+            ..opt.with_roll_args(&replayed)
+        };
+        return run(&opt, out);
+    }
+
+    let input: Vec<String> = opt
+        .input
+        .iter()
+        .flat_map(|input| split_exprs(input))
+        .map(str::to_string)
+        .collect();
+    if input != opt.input {
+        let opt = Opt {
+            input,
+            ..opt.clone()
+        };
+        return run(&opt, out);
+    }
+
+    if opt.crit_chance {
+        writeln!(out, "{:.0}%", crit_chance(opt.crit_range)? * 100.0)?;
+        return Ok(());
+    }
+
+    if let (Some(path), true, true) = (&opt.session, opt.session_reset, opt.input.is_empty()) {
+        Session::default().save(path)?;
+        writeln!(out, "Session reset")?;
+        return Ok(());
+    }
+
+    if opt.input.is_empty() && opt.savage.is_none() {
+        bail!("No expression to roll: pass one, or set {}", DEFAULT_VAR);
+    }
+
+    if opt.banner() {
+        writeln!(out, "Critical Hit!")?;
+    }
+    let crit = match opt.crit {
+        true => 2,
+        false => 1,
+    };
+
+    let queries = opt
+        .input
+        .iter()
+        .map(|input| Query::parse(input))
+        .collect::<Result<Vec<Option<Query>>>>()?;
+    if queries.iter().any(Option::is_some) {
+        for (input, query) in opt.input.iter().zip(queries) {
+            let mut query =
+                query.with_context(|| format!("Cannot roll {:?} alongside a query", input))?;
+            if opt.add != 0 {
+                query.roll.constants.push(opt.add);
+            }
+            query.roll = transform(opt, input, &query.roll)?;
+            let chance = query.probability(crit, opt.faces_limit())?;
+            writeln!(out, "{:.2}%", chance * 100.0)?;
+        }
+        return Ok(());
+    }
+
+    let aliases = opt.aliases()?;
+    let exprs = opt
+        .input
+        .iter()
+        .map(|input| resolve(input, &aliases))
+        .collect::<Result<Vec<&str>>>()?;
+    let rolls = exprs
+        .iter()
+        .zip(&opt.input)
+        .map(|(expr, input)| transform(opt, input, &prepare(expr, opt.add)?))
+        .collect::<Result<Vec<Roll>>>()?;
+    if opt.monster_average {
+        for roll in &rolls {
+            writeln!(out, "{}", opt.total(roll.average(crit)))?;
+        }
+        return Ok(());
+    }
+
+    if opt.stats_detailed {
+        for roll in &rolls {
+            for (value, chance) in roll.distribution(crit, opt.faces_limit())?.iter() {
+                writeln!(out, "{}: {:.2}%", opt.total(value), chance * 100.0)?;
+            }
+        }
+        return Ok(());
+    }
+
+    if opt.stats {
+        for (input, roll) in opt.input.iter().zip(&rolls) {
+            if rolls.len() > 1 {
+                writeln!(out, "{}:", input)?;
+            }
+            print_stats(out, opt, roll, crit)?;
+        }
+        return Ok(());
+    }
+
+    if opt.compare {
+        return print_compare(out, opt, &rolls, crit);
+    }
+
+    if let Some(budget) = opt.max_budget {
+        for (input, roll) in opt.input.iter().zip(&rolls) {
+            roll.validate()?;
+            let max = roll.max_total(crit);
+            if max > budget {
+                bail!("{} can total {}, over the budget of {}", input, max, budget);
+            }
+            writeln!(out, "OK: {} can total at most {}", input, opt.total(max))?;
+        }
+        return Ok(());
+    }
+
+    if opt.normalize {
+        for roll in &rolls {
+            writeln!(out, "{}", roll.to_canonical())?;
+        }
+        return Ok(());
+    }
+
+    if opt.dry {
+        for (input, roll) in opt.input.iter().zip(&rolls) {
+            if rolls.len() > 1 {
+                writeln!(out, "{}:", input)?;
+            }
+            print_dry(out, roll, crit)?;
+        }
+        return Ok(());
+    }
+
+    let (seed, mut rng) = opt.rng()?;
+    if let (true, Some(seed)) = (opt.verbose, seed) {
+        writeln!(out, "Seed: {}", seed)?;
+    }
+
+    if let Some(trait_die) = &opt.savage {
+        let res = savage_roll(trait_die, opt.add)?.cast(crit, &mut rng)?;
+        print_result(out, opt, &res)?;
+        print_total(out, opt, &res)?;
+        writeln!(out, "{}", savage_outcome(res.total))?;
+        return Ok(());
+    }
+
+    if opt.confirm_crit {
+        let target = opt.target.expect("--confirm-crit requires --target");
+        return print_confirm_crit(out, opt, &rolls, target, &mut rng);
+    }
+
+    if let Some(target) = opt.roll_until {
+        for (input, roll) in opt.input.iter().zip(&rolls) {
+            if rolls.len() > 1 {
+                writeln!(out, "{}:", input)?;
+            }
+            print_roll_until(out, opt, roll, target, crit, &mut rng)?;
+        }
+        return Ok(());
+    }
+
+    if opt.opposed {
+        if rolls.len() != 2 {
+            bail!(
+                "--opposed needs exactly two expressions, got {}",
+                rolls.len()
+            );
+        }
+        let results = rolls
+            .iter()
+            .map(|roll| roll.cast(crit, &mut rng))
+            .collect::<Result<Vec<RollResult>>>()?;
+        return print_opposed(out, opt, &results);
+    }
+
+    let table = match &opt.table {
+        Some(path) => Some(Table::parse(
+            &std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?,
+        )?),
+        None => None,
+    };
+
+    let mut session = match (&opt.session, opt.session_reset) {
+        (Some(path), false) => Some(Session::load(path)?),
+        (Some(_), true) => Some(Session::default()),
+        (None, _) => None,
+    };
+
+    let crit_die = match &opt.crit_mult_die {
+        Some(input) => {
+            let die = parse(input)?;
+            die.validate()?;
+            if die.min_total(1) < 1 {
+                bail!("--crit-mult-die {} can roll a multiplier below 1", input);
+            }
+            Some(die)
+        }
+        None => None,
+    };
+
+    for (input, roll) in opt.input.iter().zip(&rolls) {
+        if rolls.len() > 1 && !opt.scripted() {
+            writeln!(out, "{}:", input)?;
+        }
+        let results = match &crit_die {
+            Some(die) => (0..opt.times)
+                .map(|_| {
+                    let mult = die.cast(1, &mut rng)?.total;
+                    Ok((Some(mult), roll.cast(mult, &mut rng)?))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => roll
+                .cast_n(opt.times as usize, crit, &mut rng)?
+                .into_iter()
+                .map(|res| (None, res))
+                .collect(),
+        };
+        for (mult, res) in results {
+            let res = match opt.group_sort {
+                true => res.group_sorted(),
+                false => res,
+            };
+            if let Some(session) = &mut session {
+                session.total += res.total;
+                session.rolls += 1;
+            }
+            if let Some(table) = &table {
+                writeln!(
+                    out,
+                    "{}: {}",
+                    opt.total(res.total),
+                    table.lookup(res.total)?
+                )?;
+            } else if opt.json {
+                writeln!(out, "{}", serde_json::to_string(&res)?)?;
+            } else if let Some(Format::Foundry) = opt.format {
+                let foundry = FoundryRoll::new(roll, &res, mult.unwrap_or(crit))?;
+                writeln!(out, "{}", serde_json::to_string(&foundry)?)?;
+            } else if opt.explain_json {
+                let steps = explain(roll, &res, crit);
+                let explained = Explained {
+                    result: &res,
+                    steps,
+                };
+                writeln!(out, "{}", serde_json::to_string(&explained)?)?;
+            } else if opt.compact {
+                writeln!(out, "{}", opt.total(res.total))?;
+            } else if opt.split_groups {
+                print_groups(out, opt, roll, &res, mult.unwrap_or(crit))?;
+            } else {
+                if let Some(mult) = mult {
+                    writeln!(out, "Crit multiplier: x{}", mult)?;
+                }
+                print_result(out, opt, &res)?;
+                print_total(out, opt, &res)?;
+                if let Some(target) = opt.tiers {
+                    writeln!(out, "{}", tier(res.total, target))?;
+                }
+            }
+        }
+    }
+
+    if let (Some(path), Some(session)) = (&opt.session, &session) {
+        session.save(path)?;
+        if !(opt.json || opt.explain_json || opt.format.is_some()) {
+            writeln!(
+                out,
+                "Session total: {} ({} rolls)",
+                opt.total(session.total),
+                session.rolls
+            )?;
+        }
+    }
+
+    if let (true, Some(seed)) = (opt.replay_token, seed) {
+        let token = ReplayToken {
+            seed,
+            exprs: exprs.iter().map(|expr| expr.to_string()).collect(),
+            args: opt.roll_args(),
+        };
+        if !(opt.json || opt.explain_json || opt.format.is_some()) {
+            writeln!(out, "Replay token: {}", token)?;
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    fn opt(args: &[&str]) -> Opt {
+        Opt::from_iter(std::iter::once("roller").chain(args.iter().cloned()))
+    }
+
+    fn capture(opt: &Opt) -> String {
+        let mut out = Vec::new();
+        run(opt, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_add_applies_after_crit() {
+        let roll = prepare("1d8+1", 3).unwrap();
+        assert_eq!(vec![1, 3], roll.constants);
+
+        let (_, mut rng) = session_rng(RngKind::Seeded, Some(5));
+        let res = roll.cast(2, &mut rng).unwrap();
+        let die = res.groups[0].rolls[0].value;
+        assert_eq!(die * 2 + 1 + 3, res.total);
+    }
+
+    #[test]
+    fn test_output_to_writer() {
+        let out = capture(&opt(&["3d4+2", "--seed", "9"]));
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(5, lines.len());
+        assert_eq!("---", lines[3]);
+
+        let dice: i32 = lines[..3].iter().map(|l| l.parse::<i32>().unwrap()).sum();
+        assert_eq!(dice + 2, lines[4].parse::<i32>().unwrap());
+    }
+
+    #[test]
+    fn test_rng() {
+        for kind in &["thread", "secure", "seeded"] {
+            let out = capture(&opt(&["2d6", "--rng", kind, "-t", "50", "--compact"]));
+            for line in out.lines() {
+                assert!((2..=12).contains(&line.parse::<i32>().unwrap()), "{}", kind);
+            }
+        }
+
+        let seeded = capture(&opt(&["10d20", "--rng", "seeded", "--seed", "8"]));
+        assert_eq!(seeded, capture(&opt(&["10d20", "--seed", "8"])));
+        assert!(capture(&opt(&["1d6", "-v", "--rng", "thread"]))
+            .lines()
+            .all(|l| !l.starts_with("Seed")));
+        assert!(capture(&opt(&["1d6", "-v", "--rng", "seeded"])).starts_with("Seed: "));
+
+        let out = capture(&opt(&["10d20", "-v"]));
+        let seed = out.lines().next().unwrap().strip_prefix("Seed: ").unwrap();
+        let replayed = capture(&opt(&["10d20", "-v", "--seed", seed]));
+        assert_eq!(out, replayed);
+
+        let mut out = Vec::new();
+        assert!(run(&opt(&["1d6", "--rng", "secure", "--seed", "8"]), &mut out).is_err());
+        assert!(Opt::from_iter_safe(&["roller", "1d6", "--rng", "fast"]).is_err());
+    }
+
+    #[test]
+    fn test_crit_mult_die() {
+        let mut seen = Vec::new();
+        for seed in 0..40 {
+            let seed = seed.to_string();
+            let args = ["3d6+2", "-c", "--crit-mult-die", "1d4", "--seed", &seed];
+            let out = capture(&opt(&args));
+            let lines: Vec<&str> = out.lines().collect();
+            let mult: i32 = lines[1]
+                .trim_start_matches("Crit multiplier: x")
+                .parse()
+                .unwrap();
+            let dice: i32 = lines[2..5].iter().map(|l| l.parse::<i32>().unwrap()).sum();
+            assert!((1..=4).contains(&mult));
+            assert_eq!((dice * mult + 2).to_string(), lines[6]);
+            seen.push(mult);
+        }
+        assert!((1..=4).all(|m| seen.contains(&m)));
+
+        let mut out = Vec::new();
+        let bad = opt(&["1d6", "-c", "--crit-mult-die", "1d4-1"]);
+        assert!(run(&bad, &mut out).is_err());
+        assert!(Opt::from_iter_safe(&["roller", "1d6", "--crit-mult-die", "1d4"]).is_err());
+    }
+
+    #[test]
+    fn test_narrate_max_min() {
+        let out = capture(&opt(&["3d1+1d1", "-v"]));
+        assert!(out.contains("\n4 dice rolled their max!\n4 dice rolled a 1\n"));
+        let out = capture(&opt(&["1d1+2d2w[0,1]", "-v"]));
+        assert!(out.contains("\n3 dice rolled their max!\n1 die rolled a 1\n"));
+        assert!(!capture(&opt(&["4d1"])).contains("max"));
+    }
+
+    #[test]
+    fn test_opposed_margin() {
+        let out = capture(&opt(&["1d20+5", "1d20+3", "--opposed", "--seed", "4"]));
+        let lines: Vec<&str> = out.lines().collect();
+        let total = |line: &str| line.rsplit(' ').next().unwrap().parse::<i32>().unwrap();
+        let (a, b) = (total(lines[0]), total(lines[1]));
+
+        match a.cmp(&b) {
+            Ordering::Equal => assert_eq!(format!("Tie at {}", a), lines[2]),
+            _ => {
+                assert!(lines[2].contains(" wins by "));
+                assert_eq!((a - b).abs(), total(lines[2]));
+            }
+        }
+    }
+
+    #[test]
+    fn test_opposed_tie() {
+        let out = capture(&opt(&["1d1+2", "+3", "--opposed"]));
+        assert_eq!("1d1+2: 3\n+3: 3\nTie at 3\n", out);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let out = capture(&opt(&["3d1+2d2w[0,1]", "--histogram"]));
+        assert!(out.contains("\nHistogram: 1:3 2:2\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_dry() {
+        let out = capture(&opt(&["2d6+1d8kh1+max(1d4,1d4)+3", "--dry"]));
+        assert_eq!(
+            "2d6: 7.00\n1d8kh1: 4.50\nmax(1d4,1d4): 2.50\nModifier: +3\nExpected: 17.00\n",
+            out
+        );
+
+        let out = capture(&opt(&["2d6+1", "--dry", "--crit"]));
+        assert_eq!(
+            "Critical Hit!\n2d6: 14.00\nModifier: +1\nExpected: 15.00\n",
+            out
+        );
+    }
+
+    #[test]
+    fn test_savage() {
+        assert_eq!("Failure", savage_outcome(3));
+        assert_eq!("Success", savage_outcome(4));
+        assert_eq!("Success", savage_outcome(7));
+        assert_eq!("Success with 1 raise", savage_outcome(8));
+        assert_eq!("Success with 2 raises", savage_outcome(15));
+
+        let roll = savage_roll("d8", 0).unwrap();
+        assert_eq!(roll, savage_roll("1d8", 0).unwrap());
+        assert_eq!(roll, parse("max(1d8!,1d6!)").unwrap());
+        assert_eq!(
+            parse("max(1d12!,1d6!)").unwrap(),
+            savage_roll("12", 0).unwrap()
+        );
+        assert_eq!(
+            parse("max(1d12!,1d6!)").unwrap(),
+            savage_roll("d12", 0).unwrap()
+        );
+        for die in &["dx", "d7", "11d6", "1d1d6", "dd6", "d", "d+6"] {
+            assert!(savage_roll(die, 0).is_err(), "{}", die);
+        }
+
+        let out = capture(&opt(&["--savage", "d8", "--seed", "11"]));
+        let lines: Vec<&str> = out.lines().collect();
+        let total: i32 = lines[lines.len() - 2].parse().unwrap();
+        assert!(lines[0].starts_with("max("));
+        assert!(lines[0].ends_with(&format!("= {}", total)));
+        assert_eq!(savage_outcome(total), lines[lines.len() - 1]);
+    }
+
+    #[test]
+    fn test_separator() {
+        assert_eq!("1\n---\n1\n", capture(&opt(&["1d1"])));
+        assert_eq!(
+            "1\n1\n=====\n2\n",
+            capture(&opt(&["2d1", "--separator", "====="]))
+        );
+        assert_eq!("1\n\n1\n", capture(&opt(&["1d1", "--separator", ""])));
+        assert_eq!("1\n1\n", capture(&opt(&["1d1", "--no-separator"])));
+    }
+
+    #[test]
+    fn test_json() {
+        let out = capture(&opt(&["1d6-2", "--json", "--times", "2", "--add", "-1"]));
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(2, lines.len());
+        for line in lines {
+            let res: RollResult = serde_json::from_str(line).unwrap();
+            assert_eq!(vec![-2, -1], res.constants);
+            assert_eq!(-3, res.modifier_total);
+            assert_eq!(res.groups[0].subtotal() - 3, res.total);
+        }
+    }
+
     #[test]
-    fn test_parse() {
-        let input = "3d4+2d8+6";
-        let res = parse(input).unwrap();
+    fn test_crit_banner() {
         assert_eq!(
-            res,
-            Roll {
-                dice: vec![Dice::D4, Dice::D4, Dice::D4, Dice::D8, Dice::D8,],
-                constants: vec![6]
+            "Critical Hit!\n1\n---\n2\n",
+            capture(&opt(&["1d1", "--crit"]))
+        );
+        for flag in &["--quiet", "--sum-only", "--compact", "--no-banner"] {
+            let out = capture(&opt(&["1d1", "--crit", flag]));
+            assert!(!out.contains("Critical Hit!"), "{}", flag);
+            assert!(out.ends_with("2\n"), "{}", flag);
+        }
+        let out = capture(&opt(&["1d1", "--crit", "--json"]));
+        let res: RollResult = serde_json::from_str(out.trim()).unwrap();
+        assert_eq!(2, res.total);
+    }
+
+    #[test]
+    fn test_default_expression() {
+        let default = || Some("2d1".to_string());
+        assert_eq!(vec!["1d1"], opt(&["1d1"]).with_default(default()).input);
+        assert_eq!(vec!["2d1"], opt(&[]).with_default(default()).input);
+        assert_eq!("2\n", capture(&opt(&["--compact"]).with_default(default())));
+
+        let mut out = Vec::new();
+        let err = run(&opt(&[]).with_default(None), &mut out).unwrap_err();
+        assert!(err.to_string().contains(DEFAULT_VAR));
+    }
+
+    #[test]
+    fn test_confirm_crit() {
+        // Every roll but a natural 1 hits and threatens, and only a natural 1 (a total of 101)
+        // fails to confirm.
+        let args = [
+            "1d20+100",
+            "1d1",
+            "--confirm-crit",
+            "--target",
+            "5",
+            "--crit-range",
+            "2",
+        ];
+        let (mut confirmed, mut fumbled, mut missed) = (0, 0, 0);
+        for seed in 0..200 {
+            let seed = seed.to_string();
+            let out = capture(&opt(&[&args[..], &["--seed", &seed]].concat()));
+            let lines: Vec<&str> = out.lines().collect();
+            match lines[0].ends_with("(natural 1)") {
+                true => {
+                    assert_eq!(vec!["Miss"], lines[1..].to_vec());
+                    missed += 1;
+                }
+                false if lines[1] == "Threat, confirmation: 101 (not confirmed)" => {
+                    assert_eq!("Damage: 1", lines[2]);
+                    fumbled += 1;
+                }
+                false => {
+                    assert!(lines[1].ends_with("(confirmed)"), "{}", out);
+                    assert_eq!("Damage: 2", lines[2]);
+                    confirmed += 1;
+                }
+            }
+        }
+        assert!(confirmed > 0 && fumbled > 0 && missed > 0);
+
+        // Only a natural 20 hits AC 30, and the confirmation then needs another.
+        let args = ["1d20", "1d1", "--confirm-crit", "--target", "30"];
+        let mut threats = 0;
+        for seed in 0..200 {
+            let seed = seed.to_string();
+            let out = capture(&opt(&[&args[..], &["--seed", &seed]].concat()));
+            let lines: Vec<&str> = out.lines().collect();
+            match lines[0] {
+                "Attack: 20 (natural 20)" => {
+                    assert!(lines[1].starts_with("Threat, confirmation: "));
+                    let damage = match lines[1].ends_with("(confirmed)") {
+                        true => "Damage: 2",
+                        false => "Damage: 1",
+                    };
+                    assert_eq!(damage, lines[2]);
+                    threats += 1;
+                }
+                _ => assert_eq!("Miss", lines[1]),
             }
+        }
+        assert!(threats > 0);
+
+        let mut out = Vec::new();
+        assert!(run(
+            &opt(&["1d20+5", "--confirm-crit", "--target", "10"]),
+            &mut out
+        )
+        .is_err());
+        assert!(run(
+            &opt(&["1d6+5", "1d8", "--confirm-crit", "--target", "10"]),
+            &mut out
         )
+        .is_err());
     }
 
     #[test]
-    fn test_const_alone() {
-        let input = "+3";
-        let caps = CONSTANTS.captures(input).unwrap();
-        assert_eq!("3", &caps["const"]);
+    fn test_table() {
+        let path = std::env::temp_dir().join(format!("roller-table-{}.txt", std::process::id()));
+        std::fs::write(&path, "1-3: Goblins\n4-5: Orcs\n").unwrap();
+        let path_arg = path.to_str().unwrap();
+
+        let out = capture(&opt(&["1d1+2", "--table", path_arg]));
+        let mut err = Vec::new();
+        let missing = run(&opt(&["1d1+5", "--table", path_arg]), &mut err);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!("3: Goblins\n", out);
+        assert!(missing.is_err());
     }
 
     #[test]
-    fn test_dice_regex() {
-        let input = "3d4";
-        let caps = DICE.captures(input).unwrap();
-        assert_eq!("3", &caps["count"]);
-        assert_eq!("d4", &caps["dtype"]);
+    fn test_query() {
+        assert_eq!("55.00%\n", capture(&opt(&["1d20+5 >= 15 ?"])));
+        assert_eq!("60.00%\n", capture(&opt(&["1d20+5 >= 15 ?", "--add", "1"])));
+        assert_eq!(
+            "8.33%\n0.00%\n",
+            capture(&opt(&["2d6 = 8 ?", "2d6 = 7 ?", "--crit", "--no-banner"]))
+        );
+        assert_eq!("66.67%\n", capture(&opt(&["1d6 >= 3 ?"])));
+        assert_eq!(
+            "100.00%\n",
+            capture(&opt(&["1d6 >= 3 ?", "--floor-die", "3"]))
+        );
+        assert_eq!(
+            "0.00%\n",
+            capture(&opt(&["1d20 >= 11 ?", "--clamp-die", "2:5"]))
+        );
+        assert_eq!("75.00%\n", capture(&opt(&["1d20 >= 11 ?", "--advantage"])));
+        assert_eq!(
+            "25.00%\n",
+            capture(&opt(&["1d20 >= 11 ?", "--disadvantage"]))
+        );
+
+        let mut out = Vec::new();
+        assert!(run(&opt(&["1d20 >= 15 ?", "1d6"]), &mut out).is_err());
     }
 
     #[test]
-    fn test_many_dice_regex() {
-        let input = "33d100";
-        let caps = DICE.captures(input).unwrap();
-        assert_eq!("33", &caps["count"]);
-        assert_eq!("d100", &caps["dtype"]);
+    fn test_format_foundry() {
+        let out = capture(&opt(&["1d20+5", "--format", "foundry", "-t", "2"]));
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(2, lines.len());
+        for line in lines {
+            let json: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!("1d20 + 5", json["formula"]);
+            let natural = json["terms"][0]["results"][0]["result"].as_i64().unwrap();
+            assert_eq!(natural + 5, json["total"].as_i64().unwrap());
+        }
+
+        assert!(Opt::from_iter_safe(&["roller", "1d6", "--format", "roll20"]).is_err());
+        let mut out = Vec::new();
+        assert!(run(&opt(&["1d6", "-c", "--format", "foundry"]), &mut out).is_err());
     }
 
     #[test]
-    fn test_const_regex() {
-        let input = "3d4+6";
-        let dcaps = DICE.captures(input).unwrap();
-        assert_eq!("3", &dcaps["count"]);
-        assert_eq!("d4", &dcaps["dtype"]);
+    fn test_explain_json() {
+        let out = capture(&opt(&[
+            "4d1dl1+max(1d1,2d1)+3-1",
+            "--explain-json",
+            "--crit",
+        ]));
+        let json: serde_json::Value = serde_json::from_str(out.trim()).unwrap();
+        assert_eq!(12, json["total"]);
+        assert_eq!(2, json["modifier_total"]);
+        assert_eq!(
+            serde_json::json!([
+                "Rolled 4d1dl1: 1 (dropped), 1, 1, 1",
+                "Kept 1, 1, 1 for 3",
+                "Evaluated max(2, 4) = 4",
+                "Crit: dice multiplied by 2, 3 to 6",
+                "Modifiers +3 -1 for +2",
+                "Total: 12",
+            ]),
+            json["steps"]
+        );
+    }
+
+    #[test]
+    fn test_hide_zeros() {
+        assert_eq!("0\n0\n1\n---\n1\n", capture(&opt(&["2d1-per1+1d1"])));
+        assert_eq!(
+            "1\n---\n1\n",
+            capture(&opt(&["2d1-per1+1d1", "--hide-zeros"]))
+        );
 
-        let ccaps = CONSTANTS.captures(input).unwrap();
-        assert_eq!("6", &ccaps["const"]);
+        let out = capture(&opt(&["8dF", "--hide-zeros", "--seed", "3"]));
+        let lines: Vec<&str> = out.lines().collect();
+        let (dice, total) = lines.split_at(lines.len() - 2);
+        let shown: Vec<i32> = dice.iter().map(|l| l.parse().unwrap()).collect();
+        assert!(shown.iter().all(|v| *v == 1 || *v == -1));
+        assert_eq!(shown.iter().sum::<i32>(), total[1].parse::<i32>().unwrap());
     }
 
     #[test]
-    fn test_multiple_dice_regex() {
-        let input = "3d4+2d8";
-        let mut caps = DICE.captures_iter(input);
-        let c1 = &caps.next().unwrap();
-        let c2 = &caps.next().unwrap();
+    fn test_alias() {
+        let args = [
+            "--alias",
+            "fireball=8d1",
+            "--alias",
+            "sneak = 2d1+1",
+            "--compact",
+        ];
+        let out = capture(&opt(&[&["fireball", "sneak", "1d1"], &args[..]].concat()));
+        assert_eq!("8\n3\n1\n", out);
 
-        assert_eq!("3", &c1["count"]);
-        assert_eq!("d4", &c1["dtype"]);
+        let mut out = Vec::new();
+        let err = run(&opt(&[&["lightning"], &args[..]].concat()), &mut out).unwrap_err();
+        assert!(err.to_string().contains("Unknown alias \"lightning\""));
+        assert!(run(&opt(&["1d6", "--alias", "fireball"]), &mut out).is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        assert_eq!(
+            "Min: 2\nMax: 12\nMean: 7.00\nStd dev: 2.42\nMode: 7\n",
+            capture(&opt(&["2d6", "--stats"]))
+        );
 
-        assert_eq!("2", &c2["count"]);
-        assert_eq!("d8", &c2["dtype"]);
+        let out = capture(&opt(&["1d6!", "--stats", "--seed", "2"]));
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!("Estimated from 10000 rolls", lines[0]);
+        assert_eq!("Min: 1", lines[1]);
+        assert_eq!(6, lines.len());
     }
 
     #[test]
-    fn test_multiple_const_regex() {
-        let input = "3d4+6+2d8+9";
-        let mut caps = DICE.captures_iter(input);
-        let d1 = &caps.next().unwrap();
-        let d2 = &caps.next().unwrap();
+    fn test_animate_off_the_terminal() {
+        // Writing to a file is never animated, whatever the test's own stdout is.
+        let args = ["4d6kh3+2", "--seed", "5", "-o", "rolls.txt"];
+        let still = capture(&opt(&args));
+        let animated = capture(&opt(&[&args[..], &["--animate"]].concat()));
+        assert_eq!(still, animated);
+        assert!(!opt(&[&args[..], &["--animate"]].concat()).animating());
+    }
 
-        assert_eq!("3", &d1["count"]);
-        assert_eq!("d4", &d1["dtype"]);
+    #[test]
+    fn test_longest_run() {
+        let out = capture(&opt(&["6d6", "--longest-run", "--seed", "6"]));
+        let lines: Vec<&str> = out.lines().collect();
+        let dice: Vec<i32> = lines[..6].iter().map(|l| l.parse().unwrap()).collect();
+        let run: Vec<String> = longest_run(&dice)
+            .unwrap()
+            .iter()
+            .map(i32::to_string)
+            .collect();
+        assert_eq!(
+            format!("Longest run: {} ({})", run.join(", "), run.len()),
+            lines[6]
+        );
+    }
+
+    #[test]
+    fn test_session() {
+        let path = std::env::temp_dir().join(format!("roller-session-{}.json", std::process::id()));
+        let path_arg = path.to_str().unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let first = capture(&opt(&["2d1+1", "--compact", "--session", path_arg]));
+        let second = capture(&opt(&[
+            "1d1",
+            "--compact",
+            "-t",
+            "2",
+            "--session",
+            path_arg,
+        ]));
+        let saved = Session::load(&path).unwrap();
+        let reset = capture(&opt(&["--session", path_arg, "--session-reset"]));
+        let cleared = Session::load(&path).unwrap();
+        let restarted = capture(&opt(&["1d1", "--session", path_arg, "--session-reset"]));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!("3\nSession total: 3 (1 rolls)\n", first);
+        assert_eq!("1\n1\nSession total: 5 (3 rolls)\n", second);
+        assert_eq!(Session { total: 5, rolls: 3 }, saved);
+        assert_eq!("Session reset\n", reset);
+        assert_eq!(Session::default(), cleared);
+        assert!(restarted.ends_with("Session total: 1 (1 rolls)\n"));
+    }
+
+    #[test]
+    fn test_symbols() {
+        assert_eq!(
+            "FFG: success, success\nSymbols: success x2\n---\n0\n",
+            capture(&opt(&["2dFFG[success]"]))
+        );
+    }
+
+    #[test]
+    fn test_split_groups() {
+        for seed in 0..10 {
+            let seed = seed.to_string();
+            let split = capture(&opt(&["3d6+2d8+2", "--split-groups", "--seed", &seed]));
+            let full = capture(&opt(&["3d6+2d8+2", "--seed", &seed]));
+            let dice: Vec<i32> = full.lines().take(5).map(|l| l.parse().unwrap()).collect();
+            assert_eq!(
+                format!(
+                    "3d6: {}\n2d8: {}\nModifiers: +2\n",
+                    dice[..3].iter().sum::<i32>(),
+                    dice[3..].iter().sum::<i32>()
+                ),
+                split
+            );
+        }
 
-        assert_eq!("2", &d2["count"]);
-        assert_eq!("d8", &d2["dtype"]);
+        assert_eq!(
+            "Critical Hit!\n2d1: 4\nmax(1d1,1d1): 2\nModifiers: +1 -3\n",
+            capture(&opt(&["2d1+max(1d1,1d1)+1-3", "--split-groups", "-c"]))
+        );
+    }
+
+    #[test]
+    fn test_with_half() {
+        assert_eq!(13, half(27));
+        assert_eq!(14, half(28));
+        assert_eq!(-2, half(-3));
+
+        for seed in 0..20 {
+            let seed = seed.to_string();
+            let out = capture(&opt(&["8d6", "--with-half", "--seed", &seed]));
+            let lines: Vec<&str> = out.lines().collect();
+            let (dice, rest) = lines.split_at(lines.len() - 3);
+            let total: i32 = rest[1].parse().unwrap();
+            assert_eq!(8, dice.len());
+            assert_eq!(
+                dice.iter().map(|l| l.parse::<i32>().unwrap()).sum::<i32>(),
+                total
+            );
+            assert_eq!(format!("On save: {}", total / 2), rest[2]);
+        }
+    }
 
-        let mut ccaps = CONSTANTS.captures_iter(input);
-        let c1 = &ccaps.next().unwrap();
-        let c2 = &ccaps.next().unwrap();
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            "2d6+5\n2d6+5\n",
+            capture(&opt(&["d6+1d6+2+3", "2d6+5", "--normalize"]))
+        );
+    }
+
+    #[test]
+    fn test_matches_roll_verbose() {
+        let (_, mut rng) = roller::seeded_rng(Some(9));
+        assert_eq!(
+            roller::roll_verbose("3d6kh2+max(1d4,1d6)+2", &mut rng).unwrap(),
+            capture(&opt(&["3d6kh2+max(1d4,1d6)+2", "--seed", "9"]))
+        );
+    }
+
+    #[test]
+    fn test_roll_until() {
+        let roll = parse("1d6").unwrap();
+        let (_, mut rng) = roller::seeded_rng(Some(21));
+        let (mut total, mut rolls) = (0, 0);
+        while total < 20 {
+            total += roll.cast(1, &mut rng).unwrap().total;
+            rolls += 1;
+        }
+        assert_eq!(
+            format!("Reached {} in {} rolls\n", total, rolls),
+            capture(&opt(&["1d6", "--roll-until", "20", "--seed", "21"]))
+        );
+        assert_eq!(
+            "Reached 1 in 1 roll\n",
+            capture(&opt(&["1d1", "--roll-until", "-3"]))
+        );
+
+        let mut out = Vec::new();
+        let err = run(&opt(&["1d6-6", "--roll-until", "1"]), &mut out).unwrap_err();
+        assert_eq!("1d6-6 did not reach 1 in 10000 rolls", err.to_string());
+    }
+
+    #[test]
+    fn test_replay_token() {
+        let args = ["4d6kh3+2", "-t", "2", "--add", "1", "--replay-token"];
+        let out = capture(&opt(&args));
+        let (rolled, token) = out.trim_end().rsplit_once('\n').unwrap();
+        let token = token.strip_prefix("Replay token: ").unwrap();
+        let parsed = token.parse::<ReplayToken>().unwrap();
+        assert_eq!(vec!["4d6kh3+2"], parsed.exprs);
+        assert_eq!(vec!["--times=2", "--add=1"], parsed.args);
+
+        let replayed = capture(&opt(&["--replay", token]));
+        assert_eq!(format!("{}\n", rolled), replayed);
+        let replayed = capture(&opt(&["--replay", token, "-t", "2", "--add", "1"]));
+        assert_eq!(format!("{}\n", rolled), replayed);
+
+        let args = [
+            "--floor-die",
+            "1",
+            "--add=-2",
+            "--replay-token",
+            "--seed",
+            "9",
+            "--",
+            "3d6-per3",
+            "-1d4",
+        ];
+        let out = capture(&opt(&args));
+        let (rolled, token) = out.trim_end().rsplit_once('\n').unwrap();
+        let token = token.strip_prefix("Replay token: ").unwrap();
+        let parsed = token.parse::<ReplayToken>().unwrap();
+        assert_eq!(vec!["3d6-per3", "-1d4"], parsed.exprs);
+        assert_eq!(9, parsed.seed);
+        for args in &[
+            vec!["--replay", token],
+            vec!["--replay", token, "--floor-die", "1", "--add=-2"],
+        ] {
+            assert_eq!(format!("{}\n", rolled), capture(&opt(args)));
+        }
+
+        let mut out = Vec::new();
+        let err = run(&opt(&["--replay", "not-a-token"]), &mut out).unwrap_err();
+        assert_eq!(r#"Malformed replay token "not-a-token""#, err.to_string());
+        assert!(run(
+            &opt(&["1d6", "--replay-token", "--rng", "secure"]),
+            &mut out
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_floor_die() {
+        let out = capture(&opt(&[
+            "3d6-per2",
+            "--floor-die",
+            "1",
+            "-t",
+            "20",
+            "--seed",
+            "4",
+        ]));
+        let values: Vec<i32> = out
+            .lines()
+            .filter(|line| *line != "---")
+            .map(|line| line.parse().unwrap())
+            .collect();
+        assert!(values
+            .chunks(4)
+            .all(|roll| roll[..3].iter().all(|v| *v >= 1)));
+        assert!(values
+            .chunks(4)
+            .all(|roll| roll[3] == roll[..3].iter().sum::<i32>()));
+
+        assert_eq!(
+            "Min: 3\nMax: 12\nMean: 6.00\nStd dev: 2.00\nMode: 6\n",
+            capture(&opt(&["3d6-per2", "--floor-die", "1", "--stats"]))
+        );
+    }
+
+    #[test]
+    fn test_comma_separated() {
+        let args = ["1d20+5, 2d6+3, 1d4,", "--seed", "3"];
+        let out = capture(&opt(&args));
+        let separate = capture(&opt(&["1d20+5", "2d6+3", "1d4", "--seed", "3"]));
+        assert_eq!(separate, out);
+        assert!(out.starts_with("1d20+5:\n"));
+        assert!(out.contains("\n2d6+3:\n"));
+        assert!(out.contains("\n1d4:\n"));
+
+        assert_eq!(
+            "2\n2\n",
+            capture(&opt(&["max(1d1,1d1)+1, ,1d{1,1}", "--compact"]))
+        );
+    }
+
+    #[test]
+    fn test_clamp_die() {
+        let out = capture(&opt(&[
+            "1d20",
+            "--clamp-die",
+            "2:5",
+            "-t",
+            "50",
+            "--compact",
+        ]));
+        assert!(out
+            .lines()
+            .all(|line| (2..=5).contains(&line.parse().unwrap())));
+        let stats = capture(&opt(&["1d20-per1+1d2", "--clamp-die", "2:5", "--stats"]));
+        assert!(stats.starts_with("Min: 4\nMax: 7\n"), "{}", stats);
+
+        assert_eq!(-3..=-1, parse_clamp("-3:-1").unwrap());
+        for input in &["5:2", "2-5", "2:", "a:5"] {
+            assert!(parse_clamp(input).is_err(), "{}", input);
+        }
+        assert_eq!(
+            "Cannot clamp dice to 5..=2: the low end is above the high end",
+            parse_clamp("5:2").unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(
+            concat!(
+                "      1d12+3  2d6+3\n",
+                "Mean    9.50  10.00\n",
+                "Min        4      5\n",
+                "Max       15     15\n",
+                "P(1d12+3 > 2d6+3): 41.67%\n",
+                "P(1d12+3 = 2d6+3): 8.33%\n",
+                "P(1d12+3 < 2d6+3): 50.00%\n",
+            ),
+            capture(&opt(&["1d12+3", "2d6+3", "--compare"]))
+        );
+
+        let out = capture(&opt(&["3d6!", "3d6!", "--compare", "--seed", "1"]));
+        let chances: Vec<f64> = out
+            .lines()
+            .filter_map(|line| line.split_once("): "))
+            .map(|(_, p)| p.trim_end_matches('%').parse().unwrap())
+            .collect();
+        assert!(out.starts_with("3d6! estimated from 10000 rolls\n"));
+        assert!((chances[0] - 50.0).abs() < 5.0 && (chances[2] - 50.0).abs() < 5.0);
+
+        let mut out = Vec::new();
+        assert!(run(&opt(&["1d6", "--compare"]), &mut out).is_err());
+    }
+
+    #[test]
+    fn test_group_sort() {
+        let out = capture(&opt(&["2d8+3d6", "--group-sort", "--seed", "6"]));
+        let lines: Vec<&str> = out.lines().collect();
+        let values: Vec<i32> = lines[..5].iter().map(|l| l.parse().unwrap()).collect();
+        assert!(values[..2].iter().all(|v| (1..=8).contains(v)));
+        assert!(values[..2].windows(2).all(|w| w[0] >= w[1]));
+        assert!(values[2..].windows(2).all(|w| w[0] >= w[1]));
+        assert_eq!(values.iter().sum::<i32>().to_string(), lines[6], "{}", out);
+
+        let unsorted = capture(&opt(&["2d8+3d6", "--seed", "6"]));
+        let mut a: Vec<&str> = unsorted.lines().collect();
+        let mut b = lines.clone();
+        a.sort();
+        b.sort();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_tiers() {
+        assert_eq!("Failure", tier(6, 10));
+        assert_eq!("Partial success", tier(7, 10));
+        assert_eq!("Partial success", tier(9, 10));
+        assert_eq!("Success", tier(10, 10));
+        assert_eq!("Success", tier(14, 10));
+        assert_eq!("Critical success", tier(15, 10));
+        assert_eq!("Success", tier(-2, -2));
+
+        assert_eq!(
+            "1\n---\n13\nCritical success\n",
+            capture(&opt(&["1d1+12", "--tiers", "8"]))
+        );
+    }
+
+    #[test]
+    fn test_advantage() {
+        for seed in 0..10 {
+            let seed = seed.to_string();
+            let out = capture(&opt(&["1d20+5+1d6", "--advantage", "--seed", &seed]));
+            assert_eq!(out, capture(&opt(&["2d20kh1+5+1d6", "--seed", &seed])));
+            assert_eq!(
+                capture(&opt(&["1d20+5", "--disadvantage", "--seed", &seed])),
+                capture(&opt(&["2d20kl1+5", "--seed", &seed]))
+            );
+        }
+
+        let out = capture(&opt(&["1d20", "--advantage", "--seed", "2"]));
+        let lines: Vec<&str> = out.lines().collect();
+        let (_, mut rng) = roller::seeded_rng(Some(2));
+        let pair = [
+            roller::roll_die(20, &mut rng).unwrap(),
+            roller::roll_die(20, &mut rng).unwrap(),
+        ];
+        let kept = pair.iter().max().unwrap().to_string();
+        assert_eq!(kept, lines[3]);
+        assert!(lines[..2].iter().any(|l| l.ends_with("(dropped)")) || pair[0] == pair[1]);
+
+        let mut out = Vec::new();
+        let err = run(&opt(&["2d6+3", "--advantage"]), &mut out).unwrap_err();
+        assert_eq!("2d6+3 has no lone d20 to roll twice", err.to_string());
+        assert!(Opt::from_iter_safe(&["roller", "1d20", "--advantage", "--disadvantage"]).is_err());
+    }
+
+    #[test]
+    fn test_constants_only() {
+        assert_eq!("5\n", capture(&opt(&["+5"])));
+        let out = capture(&opt(&["3", "-v"]));
+        assert_eq!(Some("3\n"), out.split_once('\n').map(|(_, rest)| rest));
+        assert_eq!("8\n", capture(&opt(&["5+3", "--compact"])));
+        assert_eq!(
+            "Min: 3\nMax: 3\nMean: 3.00\nStd dev: 0.00\nMode: 3\n",
+            capture(&opt(&["3", "--stats"]))
+        );
+        assert_eq!("1\n---\n4\n", capture(&opt(&["3+1d1"])));
+    }
+
+    #[test]
+    fn test_best_effort() {
+        let mut out = Vec::new();
+        assert!(run(&opt(&["20000d1!", "--compact"]), &mut out).is_err());
+        assert_eq!(
+            "10000\n",
+            capture(&opt(&["20000d1!", "--best-effort", "--compact"]))
+        );
+
+        assert!(run(&opt(&["20000d1 = 10000 ?"]), &mut out).is_err());
+        assert_eq!(
+            "100.00%\n",
+            capture(&opt(&["20000d1 = 10000 ?", "--best-effort"]))
+        );
+    }
+
+    #[test]
+    fn test_max_budget() {
+        assert_eq!(
+            "OK: 2d6+3 can total at most 15\n",
+            capture(&opt(&["2d6+3", "--max-budget", "15"]))
+        );
+
+        let mut out = Vec::new();
+        let err = run(&opt(&["2d6+3", "--max-budget", "14"]), &mut out).unwrap_err();
+        assert_eq!("2d6+3 can total 15, over the budget of 14", err.to_string());
+        assert!(run(&opt(&["1d6!", "--max-budget", "100"]), &mut out).is_err());
+
+        let mut err = Vec::new();
+        let result = run(&opt(&["2d6+3", "--max-budget", "14"]), &mut out);
+        assert_eq!(1, exit_code(result, &mut err));
+        assert_eq!(
+            "2d6+3 can total 15, over the budget of 14\n",
+            String::from_utf8(err).unwrap()
+        );
+        let result = run(&opt(&["2d6+3", "--max-budget", "15"]), &mut out);
+        assert_eq!(0, exit_code(result, &mut Vec::new()));
+    }
+
+    #[test]
+    fn test_group_digits() {
+        assert_eq!("1,234,567", group_digits(1_234_567));
+        assert_eq!("-12,345", group_digits(-12_345));
+        assert_eq!("999", group_digits(999));
+        assert_eq!("1,000", group_digits(1000));
+        assert_eq!("0", group_digits(0));
+    }
+
+    #[test]
+    fn test_group_digits_total() {
+        let out = capture(&opt(&["1000d1+234567", "--group-digits"]));
+        assert!(out.ends_with("---\n235,567\n"), "{}", out);
+
+        for args in &[
+            vec!["1000d1+234567", "--group-digits", "--compact"],
+            vec!["1000d1+234567", "--group-digits", "--sum-only"],
+            vec!["1000d1+234567", "--compact"],
+        ] {
+            assert_eq!("235567\n", capture(&opt(args)));
+        }
+        let out = capture(&opt(&["1000d1+234567", "--group-digits", "--json"]));
+        assert!(out.contains("\"total\":235567"), "{}", out);
+    }
+
+    #[test]
+    fn test_output_file_append() {
+        let path = std::env::temp_dir().join(format!("roller-test-{}.txt", std::process::id()));
+        let path_arg = path.to_str().unwrap();
+
+        for args in &[
+            vec!["1d1", "-o", path_arg],
+            vec!["1d1+1", "-o", path_arg, "--append"],
+        ] {
+            let opt = opt(args);
+            let mut out = output(&opt).unwrap();
+            run(&opt, &mut out).unwrap();
+        }
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
 
-        assert_eq!("6", &c1["const"]);
-        assert_eq!("9", &c2["const"]);
+        assert_eq!("1\n---\n1\n1\n---\n2\n", written);
     }
 }
@@ -0,0 +1,125 @@
+use crate::error::{Error, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// A seed and the expressions rolled with it, packed into one string for sharing an exact
+/// roll. The token is the seed in hex followed by each expression's bytes in hex, all joined
+/// by dots: `2a.3164362b33` is `1d6+3` rolled with seed 42. Any command-line arguments that
+/// changed the totals, such as `--add=1`, follow an underscore in the same way.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplayToken {
+    pub seed: u64,
+    pub exprs: Vec<String>,
+    pub args: Vec<String>,
+}
+
+impl fmt::Display for ReplayToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:x}", self.seed)?;
+        for expr in &self.exprs {
+            write!(f, ".{}", encode(expr))?;
+        }
+        if !self.args.is_empty() {
+            let args: Vec<String> = self.args.iter().map(|arg| encode(arg)).collect();
+            write!(f, "_{}", args.join("."))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ReplayToken {
+    type Err = anyhow::Error;
+
+    fn from_str(token: &str) -> Result<Self> {
+        let malformed = || Error::MalformedToken(token.to_string());
+        let decode_all = |parts: std::str::Split<'_, char>| {
+            let all = parts
+                .map(|hex| decode(hex).ok_or_else(malformed))
+                .collect::<std::result::Result<Vec<String>, Error>>()?;
+            match all.is_empty() || all.iter().any(String::is_empty) {
+                true => Err(malformed()),
+                false => Ok(all),
+            }
+        };
+
+        let token = token.trim();
+        let (rolled, args) = match token.split_once('_') {
+            Some((rolled, args)) => (rolled, Some(args)),
+            None => (token, None),
+        };
+        let mut parts = rolled.split('.');
+        let seed = parts
+            .next()
+            .and_then(|seed| u64::from_str_radix(seed, 16).ok())
+            .ok_or_else(malformed)?;
+        Ok(ReplayToken {
+            seed,
+            exprs: decode_all(parts)?,
+            args: match args {
+                Some(args) => decode_all(args.split('.'))?,
+                None => Vec::new(),
+            },
+        })
+    }
+}
+
+fn encode(s: &str) -> String {
+    s.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode(hex: &str) -> Option<String> {
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_token_round_trip() {
+        let token = ReplayToken {
+            seed: 42,
+            exprs: vec!["1d6+3".to_string(), "max(1d20,1d20)-2".to_string()],
+            args: Vec::new(),
+        };
+        assert!(token.to_string().starts_with("2a.3164362b33."));
+        assert_eq!(token, token.to_string().parse().unwrap());
+
+        let token = ReplayToken {
+            seed: 42,
+            exprs: vec!["1d6".to_string()],
+            args: vec!["--add=-1".to_string(), "--crit".to_string()],
+        };
+        assert_eq!("2a.316436_2d2d6164643d2d31.2d2d63726974", token.to_string());
+        assert_eq!(token, token.to_string().parse().unwrap());
+    }
+
+    #[test]
+    fn test_malformed_token() {
+        for token in &[
+            "",
+            "2a",
+            "2a.",
+            "zz.3164",
+            "2a.316",
+            "2a.31g4",
+            "2a.ff",
+            "2a.31..32",
+            "2a.31_",
+            "2a_2d",
+            "2a.31_2d..2d",
+            "2a.31_2d_2d",
+        ] {
+            let err = token.parse::<ReplayToken>().unwrap_err();
+            assert!(
+                matches!(err.downcast_ref::<Error>(), Some(Error::MalformedToken(_))),
+                "{:?}",
+                token
+            );
+        }
+    }
+}
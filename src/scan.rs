@@ -0,0 +1,508 @@
+//! Finds the parts of an expression. Each `Pattern` pairs a regex with a hand-written scanner
+//! that matches exactly the same text; the scanner is used instead of the regex when the
+//! crate is built with the `no-regex` feature, which drops the `regex` and `once_cell`
+//! dependencies.
+
+use std::ops::{Index, Range};
+
+#[cfg(all(not(feature = "no-regex"), not(feature = "regex")))]
+compile_error!("Enable the default features, or `no-regex` for the hand-written parser");
+
+#[cfg(not(feature = "no-regex"))]
+use once_cell::sync::OnceCell;
+#[cfg(not(feature = "no-regex"))]
+use regex::Regex;
+
+type Scan = for<'t> fn(&'t str, usize) -> Option<Captures<'t>>;
+
+pub(crate) struct Pattern {
+    #[cfg(not(feature = "no-regex"))]
+    source: &'static str,
+    #[cfg(not(feature = "no-regex"))]
+    regex: OnceCell<Regex>,
+    /// Finds the first match at or after a byte offset.
+    #[cfg_attr(not(feature = "no-regex"), allow(dead_code))]
+    scan: Scan,
+}
+
+impl Pattern {
+    pub(crate) const fn new(source: &'static str, scan: Scan) -> Self {
+        #[cfg(feature = "no-regex")]
+        let _ = source;
+        Pattern {
+            #[cfg(not(feature = "no-regex"))]
+            source,
+            #[cfg(not(feature = "no-regex"))]
+            regex: OnceCell::new(),
+            scan,
+        }
+    }
+
+    #[cfg(not(feature = "no-regex"))]
+    fn regex(&'static self) -> &'static Regex {
+        self.regex.get_or_init(|| {
+            Regex::new(self.source)
+                .unwrap_or_else(|e| panic!("Failed to compile {:?}: {}", self.source, e))
+        })
+    }
+
+    #[cfg(not(feature = "no-regex"))]
+    fn convert<'t>(&'static self, text: &'t str, c: regex::Captures<'t>) -> Captures<'t> {
+        let groups = self
+            .regex()
+            .capture_names()
+            .flatten()
+            .filter_map(|name| c.name(name).map(|m| (name, m.range())))
+            .collect();
+        Captures {
+            text,
+            whole: c.get(0).unwrap().range(),
+            groups,
+        }
+    }
+
+    pub(crate) fn captures<'t>(&'static self, text: &'t str) -> Option<Captures<'t>> {
+        #[cfg(not(feature = "no-regex"))]
+        return self.regex().captures(text).map(|c| self.convert(text, c));
+        #[cfg(feature = "no-regex")]
+        return (self.scan)(text, 0);
+    }
+
+    /// Every non-overlapping match, left to right.
+    pub(crate) fn captures_iter<'t>(
+        &'static self,
+        text: &'t str,
+    ) -> std::vec::IntoIter<Captures<'t>> {
+        #[cfg(not(feature = "no-regex"))]
+        let all: Vec<Captures> = self
+            .regex()
+            .captures_iter(text)
+            .map(|c| self.convert(text, c))
+            .collect();
+        #[cfg(feature = "no-regex")]
+        let all = self.scan_all(text);
+        all.into_iter()
+    }
+
+    #[cfg_attr(not(feature = "no-regex"), allow(dead_code))]
+    fn scan_all<'t>(&self, text: &'t str) -> Vec<Captures<'t>> {
+        let mut all = Vec::new();
+        let mut at = 0;
+        while let Some(c) = (self.scan)(text, at) {
+            at = c.whole.end.max(c.whole.start + 1);
+            all.push(c);
+        }
+        all
+    }
+
+    /// The text with every match replaced by `with`.
+    pub(crate) fn replace_all(&'static self, text: &str, with: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut last = 0;
+        for c in self.captures_iter(text) {
+            out.push_str(&text[last..c.whole.start]);
+            out.push_str(with);
+            last = c.whole.end;
+        }
+        out.push_str(&text[last..]);
+        out
+    }
+}
+
+/// A match and its named groups, as in `regex::Captures`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Captures<'t> {
+    text: &'t str,
+    whole: Range<usize>,
+    groups: Vec<(&'static str, Range<usize>)>,
+}
+
+impl<'t> Captures<'t> {
+    fn new(text: &'t str, whole: Range<usize>) -> Self {
+        Captures {
+            text,
+            whole,
+            groups: Vec::new(),
+        }
+    }
+
+    fn with(mut self, name: &'static str, range: Range<usize>) -> Self {
+        self.groups.push((name, range));
+        self
+    }
+
+    pub(crate) fn whole(&self) -> Match<'t> {
+        Match {
+            text: self.text,
+            range: self.whole.clone(),
+        }
+    }
+
+    pub(crate) fn name(&self, name: &str) -> Option<Match<'t>> {
+        self.groups
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, range)| Match {
+                text: self.text,
+                range: range.clone(),
+            })
+    }
+}
+
+impl<'t> Index<&str> for Captures<'t> {
+    type Output = str;
+
+    fn index(&self, name: &str) -> &str {
+        match self.name(name) {
+            Some(m) => &self.text[m.range],
+            None => panic!("no group named '{}'", name),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Match<'t> {
+    text: &'t str,
+    range: Range<usize>,
+}
+
+impl<'t> Match<'t> {
+    pub(crate) fn start(&self) -> usize {
+        self.range.start
+    }
+
+    pub(crate) fn end(&self) -> usize {
+        self.range.end
+    }
+
+    pub(crate) fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    pub(crate) fn as_str(&self) -> &'t str {
+        &self.text[self.range.clone()]
+    }
+}
+
+// The scanners below follow their regexes term by term. Every term after the first required
+// one is optional or can't be shortened to help the rest match, so taking each greedily
+// finds the same match the regex would.
+
+/// The end of the run of ASCII digits starting at `at`.
+fn digits(b: &[u8], at: usize) -> usize {
+    at + b[at.min(b.len())..]
+        .iter()
+        .take_while(|c| c.is_ascii_digit())
+        .count()
+}
+
+/// The end of the run of spaces and tabs starting at `at`.
+fn spaces(b: &[u8], at: usize) -> usize {
+    at + b[at.min(b.len())..]
+        .iter()
+        .take_while(|c| matches!(c, b' ' | b'\t'))
+        .count()
+}
+
+/// The end of a bracketed list opening at `at`, such as `[1,2]`, and the range inside it.
+fn bracketed(b: &[u8], at: usize, open: u8, close: u8) -> Option<(usize, Range<usize>)> {
+    if b.get(at) != Some(&open) {
+        return None;
+    }
+    let len = b[at + 1..].iter().position(|c| *c == close)?;
+    Some((at + len + 2, at + 1..at + 1 + len))
+}
+
+/// The end of `[+-]per\d+` starting at `at`.
+fn per(b: &[u8], at: usize) -> Option<usize> {
+    match b.get(at) {
+        Some(b'+') | Some(b'-') if b[at + 1..].starts_with(b"per") => {
+            let end = digits(b, at + 4);
+            Some(end).filter(|end| *end > at + 4)
+        }
+        _ => None,
+    }
+}
+
+fn first<'t>(
+    text: &'t str,
+    from: usize,
+    at: impl Fn(&'t str, usize) -> Option<Captures<'t>>,
+) -> Option<Captures<'t>> {
+    (from..text.len()).find_map(|start| at(text, start))
+}
+
+pub(crate) fn dice(text: &str, from: usize) -> Option<Captures<'_>> {
+    first(text, from, dice_at)
+}
+
+fn dice_at(text: &str, start: usize) -> Option<Captures<'_>> {
+    let b = text.as_bytes();
+    let kind = digits(b, start);
+    if !matches!(b.get(kind), Some(b'd') | Some(b'u')) {
+        return None;
+    }
+    let sides = kind + 1;
+    let mut sizes = None;
+    let mut at = match b.get(sides) {
+        Some(c) if c.is_ascii_digit() => digits(b, sides),
+        Some(b'F') => sides + 1,
+        Some(b'{') => {
+            let (end, inside) = bracketed(b, sides, b'{', b'}')?;
+            sizes = Some(inside);
+            end
+        }
+        _ => return None,
+    };
+    let mut c = Captures::new(text, start..at)
+        .with("count", start..kind)
+        .with("dtype", kind..at)
+        .with("kind", kind..kind + 1)
+        .with("sides", sides..at);
+    if let Some(sizes) = sizes {
+        c = c.with("sizes", sizes);
+    }
+
+    for (marker, name) in &[(b'w', "weights"), (b'r', "reroll")] {
+        if b.get(at) == Some(marker) {
+            if let Some((end, inside)) = bracketed(b, at + 1, b'[', b']') {
+                c = c.with(name, inside);
+                at = end;
+            }
+        }
+    }
+    if b[at..].starts_with(b"-max") {
+        c = c.with("cursed", at..at + 4);
+        at += 4;
+    }
+    if b.get(at) == Some(&b'!') {
+        let end = match b.get(at + 1) {
+            Some(b'h') => at + 2,
+            _ => at + 1,
+        };
+        c = c.with("explode", at..end);
+        at = end;
+        let cap = digits(b, at + 1);
+        if b.get(at) == Some(&b'c') && cap > at + 1 {
+            c = c.with("cap", at + 1..cap);
+            at = cap;
+        }
+    }
+    if let Some(end) = per(b, at) {
+        c = c.with("per_first", at..end);
+        at = end + text[end..].len() - text[end..].trim_start().len();
+    }
+    let keep = match (b.get(at), b.get(at + 1)) {
+        _ if b[at..].starts_with(b"klnz") => Some(at + 4),
+        (Some(b'k'), Some(b'h')) | (Some(b'k'), Some(b'l')) => Some(digits(b, at + 2)),
+        (Some(b'd'), Some(b'h')) | (Some(b'd'), Some(b'l')) => Some(digits(b, at + 2)),
+        (Some(b'-'), Some(b'l')) if digits(b, at + 2) > at + 2 => Some(digits(b, at + 2)),
+        _ => None,
+    };
+    if let Some(end) = keep {
+        c = c.with("keep", at..end);
+        at = end;
+    }
+    if let Some(end) = per(b, at) {
+        c = c.with("per", at..end);
+        at = end;
+    }
+    if b.get(at) == Some(&b'+') {
+        at += 1;
+    }
+    c.whole = start..at;
+    Some(c)
+}
+
+pub(crate) fn constant(text: &str, from: usize) -> Option<Captures<'_>> {
+    first(text, from, |text, start| {
+        let b = text.as_bytes();
+        let sign = match b[start] {
+            b'+' | b'-' => start + 1,
+            _ if start == 0 => start,
+            _ => return None,
+        };
+        let number = spaces(b, sign);
+        let end = digits(b, number);
+        let whole = match b.get(spaces(b, end)) {
+            _ if end == number => return None,
+            None => spaces(b, end),
+            Some(b'+') | Some(b'-') => spaces(b, end) + 1,
+            Some(_) => return None,
+        };
+        Some(
+            Captures::new(text, start..whole)
+                .with("sign", start..sign)
+                .with("const", number..end),
+        )
+    })
+}
+
+pub(crate) fn malformed_die(text: &str, from: usize) -> Option<Captures<'_>> {
+    first(text, from, |text, start| {
+        let b = text.as_bytes();
+        let kind = digits(b, start);
+        if kind == start || !matches!(b.get(kind), Some(b'd') | Some(b'u')) {
+            return None;
+        }
+        let whole = match text[kind + 1..].chars().next() {
+            None => kind + 1,
+            Some(c) if c.is_ascii_digit() => return None,
+            Some(c) => kind + 1 + c.len_utf8(),
+        };
+        Some(Captures::new(text, start..whole).with("token", start..kind + 1))
+    })
+}
+
+pub(crate) fn symbols(text: &str, from: usize) -> Option<Captures<'_>> {
+    first(text, from, |text, start| {
+        let b = text.as_bytes();
+        let d = digits(b, start);
+        if d == start || b.get(d) != Some(&b'd') || !b.get(d + 1)?.is_ascii_uppercase() {
+            return None;
+        }
+        let rest = b[d + 2..]
+            .iter()
+            .take_while(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || **c == b'_')
+            .count();
+        let name = d + 1..d + 2 + rest;
+        if rest == 0 {
+            return None;
+        }
+        let (end, faces) = bracketed(b, name.end, b'[', b']')?;
+        Some(
+            Captures::new(text, start..end)
+                .with("count", start..d)
+                .with("name", name)
+                .with("faces", faces),
+        )
+    })
+}
+
+pub(crate) fn function(text: &str, from: usize) -> Option<Captures<'_>> {
+    first(text, from, |text, start| {
+        ["max", "min", "avg", "crit", "best"]
+            .iter()
+            .find_map(|name| {
+                let end = start + name.len();
+                let b = text.as_bytes();
+                match b[start..].starts_with(name.as_bytes()) && b.get(end) == Some(&b'(') {
+                    true => Some(Captures::new(text, start..end + 1).with("name", start..end)),
+                    false => None,
+                }
+            })
+    })
+}
+
+/// Anchored at the start, so only a search from 0 can match.
+pub(crate) fn query(text: &str, from: usize) -> Option<Captures<'_>> {
+    if from > 0 {
+        return None;
+    }
+    for (i, ch) in text.char_indices() {
+        for op in &[">=", "<=", ">", "<", "="] {
+            if !text[i..].starts_with(op) {
+                continue;
+            }
+            let rest = &text[i + op.len()..];
+            let target = i + op.len() + rest.len() - rest.trim_start().len();
+            let signed = target + text[target..].starts_with('-') as usize;
+            let end = digits(text.as_bytes(), signed);
+            if end > signed
+                && text[end..]
+                    .trim_start()
+                    .strip_prefix('?')
+                    .map(str::trim_start)
+                    == Some("")
+            {
+                return Some(
+                    Captures::new(text, 0..text.len())
+                        .with("roll", 0..i)
+                        .with("op", i..i + op.len())
+                        .with("target", target..end),
+                );
+            }
+        }
+        if ch == '\n' {
+            return None;
+        }
+    }
+    None
+}
+
+#[cfg(all(test, not(feature = "no-regex")))]
+mod test {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Every pattern the crate defines, so each scanner can be checked against its regex.
+    fn patterns() -> Vec<&'static Pattern> {
+        vec![
+            &crate::DICE,
+            &crate::CONSTANTS,
+            &crate::MALFORMED_DIE,
+            &crate::SYMBOLS,
+            &crate::FUNCTIONS,
+            &crate::analysis::QUERY,
+        ]
+    }
+
+    fn matches_regex(pattern: &'static Pattern, text: &str) {
+        let mut expected: Vec<Captures> = pattern.captures_iter(text).collect();
+        let mut scanned = pattern.scan_all(text);
+        for c in expected.iter_mut().chain(scanned.iter_mut()) {
+            c.groups.sort_by_key(|(name, _)| *name);
+        }
+        assert_eq!(expected, scanned, "{:?} on {:?}", pattern.source, text);
+    }
+
+    #[test]
+    fn test_scanners_match_regexes() {
+        let inputs = [
+            "3d4+2d8+6",
+            "4d6dl1+1d8!+2",
+            "d6+1d6+2+3",
+            "12d20kh1-per2 +3",
+            "1d{6,8,10}!c20klnz+per1",
+            "2d6w[1, 2,3,4,5,6]r[1,2]-max!hc9+per1  dl1-per2+",
+            "max(1d20,2d8)+min(1d12,crit(1d4))-3+avg(2d8)+best(3,4d6dl1)",
+            "2dFFG[blank,success]+3dSW_2[a]+1dX[a]",
+            "3d",
+            "3db",
+            "1d[6-10]",
+            "+2+3-4+5d6",
+            "1d20+5 >= 15 ?",
+            "2d6 = -7?",
+            "2d6 << 7 ?",
+            "1d6\n>= 3 ?",
+            "4dF+1u6-",
+            "3d6-l1+2d6-l-1d4!-l12+per1",
+            "3",
+            "5+3-1d6+2",
+            "12d6+1",
+            " 1d6 + 2 -\t3 ",
+            "  4 - 1d4",
+        ];
+        for pattern in patterns() {
+            for input in &inputs {
+                matches_regex(pattern, input);
+            }
+        }
+
+        let alphabet: Vec<char> = "0123456789dduukhlnzFFwr[],{}!c+-p e max(min)avg?<>=\n"
+            .chars()
+            .chain("-per".chars())
+            .collect();
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..20_000 {
+            let len = rng.gen_range(1, 16);
+            let text: String = (0..len)
+                .map(|_| alphabet[rng.gen_range(0, alphabet.len())])
+                .collect();
+            for pattern in patterns() {
+                matches_regex(pattern, &text);
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use crate::error::{Error, Result};
+use std::ops::RangeInclusive;
+
+/// A random table mapping rolled totals to entries, one `1-3: Goblins` or `4: Orcs` per line.
+/// Blank lines and lines starting with `#` are skipped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Table {
+    entries: Vec<(RangeInclusive<i32>, String)>,
+}
+
+impl Table {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let malformed = || Error::MalformedTableLine {
+                line: i + 1,
+                text: line.to_string(),
+            };
+
+            let (range, entry) = line.split_once(':').ok_or_else(malformed)?;
+            let bound = |s: &str| s.trim().parse::<i32>().map_err(|_| malformed());
+            let (low, high) = match range.split_once('-') {
+                Some((low, high)) => (bound(low)?, bound(high)?),
+                None => (bound(range)?, bound(range)?),
+            };
+            if low > high {
+                return Err(malformed().into());
+            }
+            entries.push((low..=high, entry.trim().to_string()));
+        }
+        Ok(Table { entries })
+    }
+
+    /// The first entry whose range contains `total`.
+    pub fn lookup(&self, total: i32) -> Result<&str> {
+        self.entries
+            .iter()
+            .find(|(range, _)| range.contains(&total))
+            .map(|(_, entry)| entry.as_str())
+            .ok_or_else(|| Error::NoTableEntry(total).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let table = Table::parse("# Encounters\n1-3: Goblins\n\n4-5: Orcs\n6: A dragon\n").unwrap();
+        for (total, entry) in &[(1, "Goblins"), (3, "Goblins"), (4, "Orcs"), (6, "A dragon")] {
+            assert_eq!(*entry, table.lookup(*total).unwrap());
+        }
+        for total in &[0, 7] {
+            match table.lookup(*total).unwrap_err().downcast_ref::<Error>() {
+                Some(Error::NoTableEntry(n)) => assert_eq!(total, n),
+                err => panic!("Expected NoTableEntry, got {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn test_table_malformed() {
+        for (text, line) in &[
+            ("1-3 Goblins", 1),
+            ("1-3: Goblins\nfour: Orcs", 2),
+            ("5-2: Orcs", 1),
+        ] {
+            match Table::parse(text).unwrap_err().downcast_ref::<Error>() {
+                Some(Error::MalformedTableLine { line: l, .. }) => assert_eq!(line, l),
+                err => panic!("Expected MalformedTableLine for {:?}, got {:?}", text, err),
+            }
+        }
+    }
+}